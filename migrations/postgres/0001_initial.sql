@@ -0,0 +1,64 @@
+-- Timestamps are stored as milliseconds since the unix epoch
+
+CREATE TABLE usrbg (
+    uid TEXT PRIMARY KEY NOT NULL,
+    img TEXT NOT NULL,
+    approved_by TEXT,
+    approved_at BIGINT,
+    source_message_id TEXT,
+    storage_key TEXT,
+    content_hash TEXT,
+    width BIGINT,
+    height BIGINT,
+    byte_size BIGINT,
+    mime_type TEXT
+);
+
+CREATE TABLE blacklist (
+    uid TEXT PRIMARY KEY NOT NULL,
+    reason TEXT,
+    moderator TEXT,
+    created_at BIGINT,
+    expires_at BIGINT
+);
+
+CREATE INDEX blacklist_expires_at ON blacklist (expires_at);
+
+CREATE TABLE requests (
+    request_message_id TEXT PRIMARY KEY NOT NULL,
+    uid TEXT NOT NULL,
+    log_message_id TEXT NOT NULL,
+    image_url TEXT NOT NULL,
+    content_hash TEXT,
+    width BIGINT,
+    height BIGINT,
+    byte_size BIGINT,
+    mime_type TEXT,
+    status TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    decided_at BIGINT,
+    decided_by TEXT
+);
+
+CREATE INDEX requests_status_uid ON requests (status, uid);
+CREATE INDEX requests_created_at ON requests (created_at);
+
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    action TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    target TEXT NOT NULL,
+    reason TEXT,
+    before_img TEXT,
+    after_img TEXT,
+    created_at BIGINT NOT NULL
+);
+
+CREATE INDEX audit_log_created_at ON audit_log (created_at);
+CREATE INDEX audit_log_target ON audit_log (target, created_at);
+CREATE INDEX audit_log_actor ON audit_log (actor, created_at);
+
+CREATE TABLE meta (
+    collection TEXT PRIMARY KEY NOT NULL,
+    schema_version BIGINT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Looks up a user's past requests
+
+CREATE INDEX requests_uid_created_at ON requests (uid, created_at);
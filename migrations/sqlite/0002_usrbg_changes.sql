@@ -0,0 +1,9 @@
+-- Change log of the usrbg table, revisions only ever increase
+
+CREATE TABLE usrbg_changes (
+    revision INTEGER PRIMARY KEY AUTOINCREMENT,
+    uid TEXT NOT NULL,
+    op TEXT NOT NULL,
+    img TEXT,
+    created_at BIGINT NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Latest change log revision. Every change locks this row until it commits, so revisions
+-- are committed in order and readers never skip one that is still being written
+
+CREATE TABLE usrbg_revision (
+    id INTEGER PRIMARY KEY,
+    revision BIGINT NOT NULL
+);
+
+INSERT INTO usrbg_revision (id, revision) SELECT 1, COALESCE(MAX(revision), 0) FROM usrbg_changes;
@@ -0,0 +1,328 @@
+use std::fmt::Display;
+
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serenity::all::{MessageId, UserId};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::ApiState,
+    moderation::{
+        approve_request, ban_user, deny_request, parse_duration, remove_background,
+        request_log_message, set_background, unban_user,
+    },
+    structs::{AuditAction, BackgroundRequest, RequestStatus},
+};
+
+/// Moderation endpoints for tools outside Discord. They run the same actions as the
+/// buttons and commands, so request log messages, the audit log and webhooks stay in sync.
+pub fn admin_routes() -> Router<ApiState> {
+    Router::new()
+        .route("/requests", get(list_pending_requests))
+        .route("/requests/{request_message_id}/approve", post(approve))
+        .route("/requests/{request_message_id}/deny", post(deny))
+        .route(
+            "/users/{uid}/background",
+            put(set_user_background).delete(remove_user_background),
+        )
+        .route("/users/{uid}/ban", put(ban).delete(unban))
+}
+
+/// Moderator the request authenticated as with an `Authorization: Bearer <token>` header
+pub struct Moderator(UserId);
+
+impl FromRequestParts<ApiState> for Moderator {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ApiState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "));
+
+        // Digests are compared so the time taken does not depend on how much of a token matched
+        let admin_token = token.and_then(|token| {
+            let digest = Sha256::digest(token);
+            state
+                .admin_tokens
+                .iter()
+                .find(|admin_token| Sha256::digest(&admin_token.token) == digest)
+        });
+
+        match admin_token {
+            Some(admin_token) => Ok(Moderator(admin_token.moderator)),
+            None => Err(admin_error(
+                StatusCode::UNAUTHORIZED,
+                "Missing or unknown token",
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AdminError {
+    error: String,
+}
+
+fn admin_error(status: StatusCode, error: impl Display) -> Response {
+    (
+        status,
+        Json(AdminError {
+            error: error.to_string(),
+        }),
+    )
+        .into_response()
+}
+
+fn already_handled() -> Response {
+    admin_error(StatusCode::CONFLICT, "Request has already been handled")
+}
+
+fn internal_error(err: anyhow::Error) -> Response {
+    println!("{:?}", err);
+    admin_error(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", err))
+}
+
+#[derive(Serialize)]
+struct PendingRequest {
+    uid: String,
+    request_message_id: String,
+    log_message_id: String,
+    image_url: String,
+    created_at: String,
+}
+
+impl From<BackgroundRequest> for PendingRequest {
+    fn from(request: BackgroundRequest) -> Self {
+        PendingRequest {
+            uid: request.uid,
+            request_message_id: request.request_message_id,
+            log_message_id: request.log_message_id,
+            image_url: request.image_url,
+            created_at: request.created_at.to_rfc3339_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Background {
+    uid: String,
+    img: String,
+}
+
+#[derive(Deserialize)]
+struct SetBackground {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct BanOptions {
+    /// e.g. `30m`, `12h`, `7d` or `2w`, permanent when unset
+    duration: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Ban {
+    uid: String,
+    reason: Option<String>,
+    expires_at: Option<String>,
+}
+
+async fn list_pending_requests(_: Moderator, State(state): State<ApiState>) -> Response {
+    let result = state.repositories.requests.pending().await;
+
+    match result {
+        Ok(requests) => Json(
+            requests
+                .into_iter()
+                .map(PendingRequest::from)
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn approve(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(request_message_id): Path<u64>,
+) -> Response {
+    let request = match pending_request(&state, request_message_id).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut log_message = match request_log_message(&state.ctx, &request).await {
+        Ok(log_message) => log_message,
+        Err(err) => return internal_error(err),
+    };
+
+    let result = approve_request(
+        &state.ctx,
+        &mut log_message,
+        &request.uid,
+        &request.image_url,
+        MessageId::new(request_message_id),
+        moderator,
+    )
+    .await;
+
+    match result {
+        Ok(Some(img)) => Json(Background {
+            uid: request.uid,
+            img,
+        })
+        .into_response(),
+        Ok(None) => already_handled(),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn deny(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(request_message_id): Path<u64>,
+) -> Response {
+    let request = match pending_request(&state, request_message_id).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut log_message = match request_log_message(&state.ctx, &request).await {
+        Ok(log_message) => log_message,
+        Err(err) => return internal_error(err),
+    };
+
+    let result = deny_request(
+        &state.ctx,
+        &mut log_message,
+        &request.uid,
+        MessageId::new(request_message_id),
+        moderator,
+    )
+    .await;
+
+    match result {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => already_handled(),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn pending_request(
+    state: &ApiState,
+    request_message_id: u64,
+) -> Result<BackgroundRequest, Response> {
+    let result = state
+        .repositories
+        .requests
+        .get(&request_message_id.to_string())
+        .await;
+
+    match result {
+        Ok(Some(request)) if request.status == RequestStatus::Pending => Ok(request),
+        Ok(Some(_)) => Err(already_handled()),
+        Ok(None) => Err(admin_error(StatusCode::NOT_FOUND, "Unknown request")),
+        Err(err) => Err(internal_error(err)),
+    }
+}
+
+async fn set_user_background(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(uid): Path<u64>,
+    Json(body): Json<SetBackground>,
+) -> Response {
+    let uid = uid.to_string();
+    let result = set_background(&state.ctx, &uid, &body.url, moderator).await;
+
+    match result {
+        Ok(img) => Json(Background { uid, img }).into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn remove_user_background(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(uid): Path<u64>,
+) -> Response {
+    let result = remove_background(
+        &state.ctx,
+        &uid.to_string(),
+        moderator,
+        AuditAction::AdminRemove,
+    )
+    .await;
+
+    match result {
+        Ok(removal) if removal.found => Json(removal).into_response(),
+        Ok(_) => admin_error(StatusCode::NOT_FOUND, "User has no background"),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn ban(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(uid): Path<u64>,
+    Json(options): Json<BanOptions>,
+) -> Response {
+    let duration = match &options.duration {
+        Some(duration) => match parse_duration(duration) {
+            Some(duration) => Some(duration),
+            None => {
+                return admin_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid duration `{}`", duration),
+                )
+            }
+        },
+        None => None,
+    };
+
+    let result = ban_user(
+        &state.ctx,
+        &uid.to_string(),
+        moderator,
+        duration,
+        options.reason,
+    )
+    .await;
+
+    match result {
+        Ok(entry) => Json(Ban {
+            uid: entry.uid,
+            reason: entry.reason,
+            expires_at: entry
+                .expires_at
+                .map(|expires_at| expires_at.to_rfc3339_string()),
+        })
+        .into_response(),
+        Err(err) => internal_error(err),
+    }
+}
+
+async fn unban(
+    Moderator(moderator): Moderator,
+    State(state): State<ApiState>,
+    Path(uid): Path<u64>,
+) -> Response {
+    let result = unban_user(&state.ctx, &uid.to_string(), moderator).await;
+
+    match result {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => admin_error(StatusCode::NOT_FOUND, "User is not banned"),
+        Err(err) => internal_error(err),
+    }
+}
@@ -0,0 +1,301 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context as AnyhowContext};
+use axum::{
+    extract::{Path, Query, State},
+    http::{
+        header::{
+            CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        },
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serenity::client::Context;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    admin_api::{admin_routes, Moderator},
+    dataset::changes_since,
+    metrics::METRICS,
+    structs::{AdminToken, Config, Http, Repositories, Usrbg, UsrbgCache},
+};
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub ctx: Context,
+    pub cache: UsrbgCache,
+    pub repositories: Repositories,
+    pub page_size: usize,
+    pub admin_tokens: Vec<AdminToken>,
+}
+
+/// Serves the HTTP API if the `http` config section is present. The public user lookups are
+/// answered from the usrbg cache, without touching the database.
+pub async fn serve_api(ctx: Context) {
+    let result = run_api(ctx).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+}
+
+async fn run_api(ctx: Context) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let http_config = data
+        .get::<Config>()
+        .context("Could not get config")?
+        .http
+        .clone();
+    let cache = data
+        .get::<UsrbgCache>()
+        .context("Could not get usrbg cache")?
+        .clone();
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    let http_config = match http_config {
+        Some(http_config) => http_config,
+        None => return Ok(()),
+    };
+
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/users", get(list_users))
+        .route("/users/{uid}", get(get_user))
+        .route("/changes", get(list_changes))
+        .route("/metrics", get(metrics))
+        .nest("/admin", admin_routes())
+        .with_state(ApiState {
+            ctx,
+            cache,
+            repositories,
+            page_size: http_config.page_size,
+            admin_tokens: http_config.admin_tokens,
+        });
+
+    let listener = tokio::net::TcpListener::bind(&http_config.bind)
+        .await
+        .with_context(|| format!("Could not listen on {}", http_config.bind))?;
+    println!("Serving HTTP API on {}", http_config.bind);
+
+    axum::serve(listener, app).await.context("HTTP API stopped")
+}
+
+/// An empty token would be matched by a bare `Bearer ` header
+pub fn check_admin_tokens(http_config: &Http) -> anyhow::Result<()> {
+    if http_config
+        .admin_tokens
+        .iter()
+        .any(|admin_token| admin_token.token.trim().is_empty())
+    {
+        bail!("http.admin_tokens contains an empty token");
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct UserBackground<'a> {
+    uid: &'a str,
+    img: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approved_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_hash: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    byte_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime_type: Option<&'a str>,
+}
+
+impl<'a> From<&'a Usrbg> for UserBackground<'a> {
+    fn from(entry: &'a Usrbg) -> Self {
+        UserBackground {
+            uid: &entry.uid,
+            img: &entry.img,
+            approved_at: entry
+                .approved_at
+                .map(|approved_at| approved_at.to_rfc3339_string()),
+            content_hash: entry.image.content_hash.as_deref(),
+            width: entry.image.width,
+            height: entry.image.height,
+            byte_size: entry.image.byte_size,
+            mime_type: entry.image.mime_type.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserPage<'a> {
+    /// Change log revision the page is current to
+    revision: i64,
+    page: usize,
+    per_page: usize,
+    total: usize,
+    users: Vec<UserBackground<'a>>,
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ChangesQuery {
+    since: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    users: usize,
+}
+
+async fn health(State(state): State<ApiState>) -> Response {
+    let dataset = state.cache.dataset.read().await;
+    Json(Health {
+        status: "ok",
+        users: dataset.entries.len(),
+    })
+    .into_response()
+}
+
+async fn get_user(
+    State(state): State<ApiState>,
+    Path(uid): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let dataset = state.cache.dataset.read().await;
+    let entry = match dataset.entries.get(&uid) {
+        Some(entry) => entry,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let body = UserBackground::from(entry);
+    let etag = match serde_json::to_vec(&body) {
+        Ok(serialized) => hex::encode(Sha256::digest(serialized)),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    cached_response(&headers, &etag, dataset.last_modified, body)
+}
+
+async fn list_users(
+    State(state): State<ApiState>,
+    Query(query): Query<PageQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(state.page_size)
+        .clamp(1, state.page_size);
+
+    let dataset = state.cache.dataset.read().await;
+    let users = dataset
+        .entries
+        .values()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .map(UserBackground::from)
+        .collect();
+
+    let body = UserPage {
+        revision: dataset.revision,
+        page,
+        per_page,
+        total: dataset.entries.len(),
+        users,
+    };
+    let etag = format!("{}-{}-{}", dataset.etag, page, per_page);
+
+    cached_response(&headers, &etag, dataset.last_modified, body)
+}
+
+/// At most a page of changes is returned, clients ask again from the last revision they
+/// received until they reach the latest revision
+async fn list_changes(
+    State(state): State<ApiState>,
+    Query(query): Query<ChangesQuery>,
+) -> Response {
+    let since = query.since.unwrap_or(0);
+    let result = changes_since(&state.repositories, since, state.page_size as i64).await;
+
+    match result {
+        Ok(changes) => ([(CACHE_CONTROL, "no-cache")], Json(changes)).into_response(),
+        Err(err) => {
+            println!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Requires an admin token, the gauges are read from the database on every scrape
+async fn metrics(_: Moderator, State(state): State<ApiState>) -> Response {
+    let result = METRICS.render(&state.repositories, &state.cache).await;
+
+    match result {
+        Ok(metrics) => (
+            [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+            metrics,
+        )
+            .into_response(),
+        Err(err) => {
+            println!("{:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Answers with `304 Not Modified` if the client's copy is still current, otherwise with
+/// the body and its validators
+fn cached_response<T: Serialize>(
+    headers: &HeaderMap,
+    etag: &str,
+    last_modified: bson::DateTime,
+    body: T,
+) -> Response {
+    let etag = format!("\"{}\"", etag);
+    // HTTP dates only have second precision
+    let last_modified =
+        UNIX_EPOCH + Duration::from_secs((last_modified.timestamp_millis() / 1000).max(0) as u64);
+
+    // If-Modified-Since is ignored when If-None-Match is present
+    let not_modified = match headers.get(IF_NONE_MATCH) {
+        Some(if_none_match) => if_none_match
+            .to_str()
+            .map(|tags| {
+                tags.split(',')
+                    .any(|tag| tag.trim() == etag || tag.trim() == "*")
+            })
+            .unwrap_or(false),
+        None => headers
+            .get(IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| httpdate::parse_http_date(since).ok())
+            .map(|since: SystemTime| last_modified <= since)
+            .unwrap_or(false),
+    };
+
+    let validators = [
+        (ETAG, etag),
+        (LAST_MODIFIED, httpdate::fmt_http_date(last_modified)),
+        (CACHE_CONTROL, "no-cache".to_owned()),
+    ];
+
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, validators).into_response()
+    } else {
+        (validators, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::{bail, Context as AnyhowContext};
+use serde::Serialize;
+
+use crate::{
+    handlers::command_parser::parse_user_id,
+    structs::{AuditAction, AuditEntry, AuditFilter, Repositories},
+};
+
+// Kept small so a full page of entries fits in a single Discord message
+pub const AUDIT_PAGE_SIZE: u64 = 5;
+/// Entries per `~audit-export` page
+pub const AUDIT_EXPORT_PAGE_SIZE: u64 = 1000;
+// Below Discord's 10 MiB attachment limit for bots
+const MAX_AUDIT_EXPORT_BYTES: usize = 8 * 1024 * 1024;
+const MAX_LISTED_REASON_LENGTH: usize = 100;
+
+/// Appends an entry to the audit log. Errors are only logged, the action has already
+/// happened by the time it is recorded.
+pub async fn append_audit_entry(repositories: &Repositories, entry: AuditEntry) {
+    let result = repositories.audit_log.append(entry).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum AuditExportFormat {
+    Csv,
+    #[default]
+    Jsonl,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditQuery {
+    pub filter: AuditFilter,
+    /// Zero based page of results
+    pub page: u64,
+    pub format: AuditExportFormat,
+}
+
+/// Parses `user:<id> mod:<id> action:<action> page:<n> format:<csv|jsonl>`, in any order
+pub fn parse_audit_query(words: &[&str]) -> anyhow::Result<AuditQuery> {
+    let mut query = AuditQuery::default();
+
+    for word in words {
+        let (key, value) = word
+            .split_once(':')
+            .with_context(|| format!("Expected key:value, got `{}`", word))?;
+
+        match key {
+            "user" => query.filter.target = Some(parse_uid(value)?),
+            "mod" => query.filter.actor = Some(parse_uid(value)?),
+            "action" => {
+                let action = AuditAction::ALL
+                    .into_iter()
+                    .find(|action| action.as_str() == value);
+                match action {
+                    Some(action) => query.filter.action = Some(action),
+                    None => bail!(
+                        "Unknown action `{}`, expected one of {}",
+                        value,
+                        AuditAction::ALL.map(|action| action.as_str()).join(", ")
+                    ),
+                }
+            }
+            "page" => {
+                let page: u64 = value
+                    .parse()
+                    .with_context(|| format!("Invalid page `{}`", value))?;
+                query.page = page.saturating_sub(1);
+            }
+            "format" => {
+                query.format = match value {
+                    "csv" => AuditExportFormat::Csv,
+                    "jsonl" => AuditExportFormat::Jsonl,
+                    _ => bail!("Unknown format `{}`, expected csv or jsonl", value),
+                }
+            }
+            _ => bail!(
+                "Unknown filter `{}`, expected user, mod, action, page or format",
+                key
+            ),
+        }
+    }
+
+    Ok(query)
+}
+
+fn parse_uid(value: &str) -> anyhow::Result<String> {
+    match parse_user_id(value.trim()) {
+        Some(user_id) => Ok(user_id.to_string()),
+        None => bail!("Invalid user id `{}`", value),
+    }
+}
+
+pub fn format_audit_entry(entry: &AuditEntry) -> String {
+    let mut line = format!(
+        "<t:{}:f> **{}** <@{}> by <@{}>",
+        entry.created_at.timestamp_millis() / 1000,
+        entry.action.as_str(),
+        entry.target,
+        entry.actor
+    );
+    match &entry.reason {
+        Some(reason) if reason.chars().count() > MAX_LISTED_REASON_LENGTH => {
+            let reason: String = reason.chars().take(MAX_LISTED_REASON_LENGTH).collect();
+            line += &format!(": {}...", reason);
+        }
+        Some(reason) => line += &format!(": {}", reason),
+        None => {}
+    }
+    if let Some(before_img) = &entry.before_img {
+        line += &format!(" [before](<{}>)", before_img)
+    }
+    if let Some(after_img) = &entry.after_img {
+        line += &format!(" [after](<{}>)", after_img)
+    }
+    line
+}
+
+#[derive(Serialize)]
+struct AuditCsvRecord<'a> {
+    created_at: String,
+    action: &'static str,
+    actor: &'a str,
+    target: &'a str,
+    reason: Option<&'a str>,
+    before_img: Option<&'a str>,
+    after_img: Option<&'a str>,
+}
+
+pub struct AuditExport {
+    pub contents: Vec<u8>,
+    pub filename: &'static str,
+    /// Number of leading entries that fit in the file
+    pub exported: usize,
+}
+
+/// Serializes audit log entries, stopping before the file would grow past what Discord
+/// accepts as an attachment
+pub fn export_audit_entries(
+    entries: &[AuditEntry],
+    format: AuditExportFormat,
+) -> anyhow::Result<AuditExport> {
+    export_audit_entries_within(entries, format, MAX_AUDIT_EXPORT_BYTES)
+}
+
+fn export_audit_entries_within(
+    entries: &[AuditEntry],
+    format: AuditExportFormat,
+    max_bytes: usize,
+) -> anyhow::Result<AuditExport> {
+    let (mut contents, filename) = match format {
+        AuditExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer.write_record([
+                "created_at",
+                "action",
+                "actor",
+                "target",
+                "reason",
+                "before_img",
+                "after_img",
+            ])?;
+            (into_csv_contents(writer)?, "audit_log.csv")
+        }
+        AuditExportFormat::Jsonl => (vec![], "audit_log.jsonl"),
+    };
+
+    let mut exported = 0;
+    for entry in entries {
+        let line = match format {
+            AuditExportFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(vec![]);
+                writer.serialize(AuditCsvRecord {
+                    created_at: entry.created_at.to_rfc3339_string(),
+                    action: entry.action.as_str(),
+                    actor: &entry.actor,
+                    target: &entry.target,
+                    reason: entry.reason.as_deref(),
+                    before_img: entry.before_img.as_deref(),
+                    after_img: entry.after_img.as_deref(),
+                })?;
+                into_csv_contents(writer)?
+            }
+            AuditExportFormat::Jsonl => {
+                let mut line = serde_json::to_vec(entry)?;
+                line.push(b'\n');
+                line
+            }
+        };
+        if contents.len() + line.len() > max_bytes {
+            break;
+        }
+        contents.extend(line);
+        exported += 1;
+    }
+
+    Ok(AuditExport {
+        contents,
+        filename,
+        exported,
+    })
+}
+
+fn into_csv_contents(writer: csv::Writer<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+    writer.into_inner().context("Could not write audit log csv")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<AuditEntry> {
+        (0..3)
+            .map(|index| {
+                let mut entry =
+                    AuditEntry::new(AuditAction::Ban, "125227483518861312", "80351110224678912");
+                entry.reason = Some(format!("reason {}", index));
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn export_contains_every_entry_under_the_limit() {
+        let export = export_audit_entries(&entries(), AuditExportFormat::Jsonl).unwrap();
+        assert_eq!(export.exported, 3);
+        assert_eq!(export.filename, "audit_log.jsonl");
+        assert_eq!(export.contents.split(|byte| *byte == b'\n').count(), 4);
+
+        let export = export_audit_entries(&entries(), AuditExportFormat::Csv).unwrap();
+        assert_eq!(export.exported, 3);
+        let contents = String::from_utf8(export.contents).unwrap();
+        assert!(contents.starts_with("created_at,action,actor,target,reason,"));
+        assert_eq!(contents.lines().count(), 4);
+    }
+
+    #[test]
+    fn export_stops_at_the_size_limit() {
+        let entries = entries();
+        let line_length = serde_json::to_vec(&entries[0]).unwrap().len() + 1;
+
+        let export =
+            export_audit_entries_within(&entries, AuditExportFormat::Jsonl, line_length * 2 + 1)
+                .unwrap();
+        assert_eq!(export.exported, 2);
+        assert!(export.contents.len() <= line_length * 2 + 1);
+
+        let export = export_audit_entries_within(&entries, AuditExportFormat::Csv, 10).unwrap();
+        assert_eq!(export.exported, 0);
+    }
+}
@@ -1,5 +1,6 @@
+use std::time::Duration;
+
 use anyhow::Context as AnyhowContext;
-use bson::doc;
 use serenity::{
     model::{
         prelude::{Member, PartialMember},
@@ -8,7 +9,11 @@ use serenity::{
     prelude::Context,
 };
 
-use crate::structs::{Collections, Config};
+use crate::structs::{Config, Repositories};
+
+// How often expired temporary bans are removed, in seconds
+const BAN_EXPIRY_CHECK_INTERVAL: u64 = 60 * 60;
+
 pub trait HasAuth {
     async fn has_auth(&self, ctx: &Context) -> anyhow::Result<bool>;
 }
@@ -48,14 +53,37 @@ pub trait IsBlacklisted {
 impl IsBlacklisted for User {
     async fn is_blacklisted(&self, ctx: &Context) -> anyhow::Result<bool> {
         let data = ctx.data.read().await;
-        let collections = data
-            .get::<Collections>()
-            .context("Could not get collections")?;
-
-        let blacklist_search_query = doc! { "uid": self.id.get().to_string() };
-        let blacklist_search_result = collections.blacklist.find_one(blacklist_search_query, None);
-        Ok(blacklist_search_result
-            .context("Could not complete blacklist search")?
-            .is_some())
+        let repositories = data
+            .get::<Repositories>()
+            .context("Could not get repositories")?
+            .clone();
+        drop(data);
+
+        let blacklist_search_result = repositories.blacklist.get(&self.id.get().to_string()).await;
+        Ok(blacklist_search_result?
+            .map(|entry| entry.is_active())
+            .unwrap_or(false))
+    }
+}
+
+pub async fn remove_expired_bans(ctx: Context) {
+    loop {
+        let data = ctx.data.read().await;
+        let repositories = data.get::<Repositories>().cloned();
+        drop(data);
+
+        match repositories {
+            Some(repositories) => {
+                let result = repositories.blacklist.delete_expired().await;
+                match result {
+                    Ok(0) => {}
+                    Ok(deleted) => println!("Removed {} expired bans", deleted),
+                    Err(err) => println!("{:?}", err),
+                }
+            }
+            None => println!("Could not get repositories"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(BAN_EXPIRY_CHECK_INTERVAL)).await;
     }
 }
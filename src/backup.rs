@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Write},
+};
+
+use anyhow::{bail, Context};
+use s3::error::S3Error;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    s3bucket::referenced_object_key,
+    structs::{Blacklist, Config, Repositories, S3Bucket, Usrbg},
+};
+
+const ARCHIVE_VERSION: u32 = 1;
+const MANIFEST_PATH: &str = "manifest.json";
+const USRBG_PATH: &str = "usrbg.jsonl";
+const BLACKLIST_PATH: &str = "blacklist.jsonl";
+const IMAGES_PREFIX: &str = "images/";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    created_at: String,
+    files: Vec<ManifestEntry>,
+    /// Bucket keys of images that were referenced but no longer in the bucket
+    #[serde(default)]
+    missing: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    sha256: String,
+    size: u64,
+    /// Bucket key and content type, only set for images
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    content_type: Option<String>,
+}
+
+/// Writes the usrbg and blacklist collections and every referenced image into a single
+/// tar + zstd archive, together with a manifest of checksums.
+pub async fn backup(
+    config: &Config,
+    bucket: &S3Bucket,
+    repositories: &Repositories,
+    archive_path: &str,
+) -> anyhow::Result<()> {
+    let file = File::create(archive_path).context("Could not create backup archive")?;
+    let encoder = zstd::Encoder::new(file, 0)?;
+    let mut archive = tar::Builder::new(encoder);
+
+    let mut manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        created_at: bson::DateTime::now().to_rfc3339_string(),
+        files: vec![],
+        missing: vec![],
+    };
+
+    let usrbg_entries = repositories.backgrounds.all().await?;
+    let blacklist_entries = repositories.blacklist.all().await?;
+
+    let image_keys: Vec<String> = usrbg_entries
+        .iter()
+        .filter_map(|entry| referenced_object_key(config, entry))
+        .collect();
+
+    let usrbg_lines = to_json_lines(&usrbg_entries)?;
+    append_file(
+        &mut archive,
+        &mut manifest,
+        USRBG_PATH.to_owned(),
+        &usrbg_lines,
+        None,
+    )?;
+    println!("Backed up {} usrbg entries", usrbg_entries.len());
+
+    let blacklist_lines = to_json_lines(&blacklist_entries)?;
+    append_file(
+        &mut archive,
+        &mut manifest,
+        BLACKLIST_PATH.to_owned(),
+        &blacklist_lines,
+        None,
+    )?;
+    println!("Backed up {} blacklist entries", blacklist_entries.len());
+
+    for key in image_keys {
+        let response = match bucket.bucket.get_object(&key).await {
+            Ok(response) => response,
+            Err(S3Error::Http(404, _)) => {
+                println!(
+                    "{} is referenced but missing from the bucket, skipping",
+                    key
+                );
+                manifest.missing.push(key);
+                continue;
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Could not download {} from bucket", key))
+            }
+        };
+
+        let content_type = response.headers().get("content-type").cloned();
+
+        let path = format!("{}{}", IMAGES_PREFIX, key.trim_start_matches('/'));
+        append_file(
+            &mut archive,
+            &mut manifest,
+            path,
+            response.bytes(),
+            Some((key, content_type)),
+        )?;
+    }
+
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = file_header(manifest_json.len() as u64);
+    archive.append_data(&mut header, MANIFEST_PATH, manifest_json.as_slice())?;
+
+    archive
+        .into_inner()?
+        .finish()
+        .context("Could not finish backup archive")?;
+
+    println!(
+        "Wrote {} files to {}",
+        manifest.files.len() + 1,
+        archive_path
+    );
+    if !manifest.missing.is_empty() {
+        println!(
+            "{} referenced images were missing from the bucket, see the manifest",
+            manifest.missing.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Replays an archive created by `backup` into an empty database and bucket.
+/// The whole archive is verified against its manifest before anything is written.
+pub async fn restore(
+    config: &Config,
+    bucket: &S3Bucket,
+    repositories: &Repositories,
+    archive_path: &str,
+) -> anyhow::Result<()> {
+    let manifest = verify_archive(archive_path)?;
+    println!(
+        "Verified {} files in {}",
+        manifest.files.len(),
+        archive_path
+    );
+    if !manifest.missing.is_empty() {
+        println!(
+            "{} referenced images were already missing when the backup was made: {:?}",
+            manifest.missing.len(),
+            manifest.missing
+        );
+    }
+
+    if !repositories.backgrounds.all().await?.is_empty()
+        || !repositories.blacklist.all().await?.is_empty()
+    {
+        bail!("Refusing to restore into a database that already contains data");
+    }
+
+    let existing_objects = bucket
+        .bucket
+        .list(
+            config
+                .storage
+                .storage_path
+                .trim_start_matches('/')
+                .to_owned(),
+            None,
+        )
+        .await
+        .context("Could not list bucket")?;
+    if existing_objects
+        .iter()
+        .any(|result| !result.contents.is_empty())
+    {
+        bail!("Refusing to restore into a bucket that already contains images");
+    }
+
+    let manifest_entries: HashMap<&str, &ManifestEntry> = manifest
+        .files
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect();
+
+    let mut archive = open_archive(archive_path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+
+        match path.as_str() {
+            USRBG_PATH => {
+                let entries: Vec<Usrbg> = from_json_lines(&contents)?;
+                println!("Restoring {} usrbg entries", entries.len());
+                for entry in entries {
+                    repositories.backgrounds.upsert(entry).await?;
+                }
+            }
+            BLACKLIST_PATH => {
+                let entries: Vec<Blacklist> = from_json_lines(&contents)?;
+                println!("Restoring {} blacklist entries", entries.len());
+                for entry in entries {
+                    repositories.blacklist.upsert(entry).await?;
+                }
+            }
+            MANIFEST_PATH => {}
+            _ => {
+                let manifest_entry = manifest_entries
+                    .get(path.as_str())
+                    .context("Archive entry missing from manifest")?;
+                let key = manifest_entry
+                    .key
+                    .as_ref()
+                    .context("Image entry has no bucket key")?;
+                let content_type = manifest_entry
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("application/octet-stream");
+
+                let response = bucket
+                    .bucket
+                    .put_object_with_content_type(key, &contents, content_type)
+                    .await
+                    .with_context(|| format!("Could not upload {} to bucket", key))?;
+                if response.status_code() != 200 {
+                    bail!("Error uploading {} to minio", key)
+                }
+            }
+        }
+    }
+
+    println!("Restore complete");
+
+    Ok(())
+}
+
+fn append_file<W: Write>(
+    archive: &mut tar::Builder<W>,
+    manifest: &mut Manifest,
+    path: String,
+    contents: &[u8],
+    image: Option<(String, Option<String>)>,
+) -> anyhow::Result<()> {
+    let mut header = file_header(contents.len() as u64);
+    archive.append_data(&mut header, &path, contents)?;
+
+    let (key, content_type) = match image {
+        Some((key, content_type)) => (Some(key), content_type),
+        None => (None, None),
+    };
+
+    manifest.files.push(ManifestEntry {
+        path,
+        sha256: hex::encode(Sha256::digest(contents)),
+        size: contents.len() as u64,
+        key,
+        content_type,
+    });
+    Ok(())
+}
+
+fn file_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(bson::DateTime::now().timestamp_millis() as u64 / 1000);
+    header
+}
+
+fn open_archive(archive_path: &str) -> anyhow::Result<tar::Archive<impl Read>> {
+    let file = File::open(archive_path).context("Could not open backup archive")?;
+    Ok(tar::Archive::new(zstd::Decoder::new(file)?))
+}
+
+fn verify_archive(archive_path: &str) -> anyhow::Result<Manifest> {
+    let mut checksums = HashMap::new();
+    let mut manifest: Option<Manifest> = None;
+
+    let mut archive = open_archive(archive_path)?;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let mut contents = vec![];
+        entry.read_to_end(&mut contents)?;
+
+        if path == MANIFEST_PATH {
+            manifest = Some(serde_json::from_slice(&contents).context("Could not parse manifest")?);
+        } else {
+            checksums.insert(path, hex::encode(Sha256::digest(&contents)));
+        }
+    }
+
+    let manifest = manifest.context("Archive has no manifest")?;
+
+    if manifest.version != ARCHIVE_VERSION {
+        bail!("Unsupported archive version {}", manifest.version);
+    }
+
+    for entry in &manifest.files {
+        match checksums.remove(&entry.path) {
+            Some(checksum) if checksum == entry.sha256 => {}
+            Some(_) => bail!("Checksum mismatch for {}", entry.path),
+            None => bail!("{} is listed in the manifest but missing", entry.path),
+        }
+    }
+
+    if let Some(path) = checksums.keys().next() {
+        bail!("{} is not listed in the manifest", path);
+    }
+
+    Ok(manifest)
+}
+
+fn to_json_lines<T: Serialize>(entries: &[T]) -> anyhow::Result<Vec<u8>> {
+    let mut lines = vec![];
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+fn from_json_lines<T: DeserializeOwned>(contents: &[u8]) -> anyhow::Result<Vec<T>> {
+    let mut entries = vec![];
+    for line in std::str::from_utf8(contents)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(line)?);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context as AnyhowContext};
+use reqwest::Client;
+use serenity::prelude::Context;
+
+use crate::{
+    retry::with_retries,
+    structs::{CdnPurge, Config, HttpClient},
+};
+
+const PURGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Purges the given URLs from the CDN in the background. Failures are retried with
+/// exponential backoff and reported to the log channel.
+pub async fn purge_cdn(ctx: &Context, urls: Vec<String>) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+
+    let purge_config = match &config.cdn_purge {
+        Some(purge_config) => purge_config.clone(),
+        None => return Ok(()),
+    };
+
+    let http_client = data
+        .get::<HttpClient>()
+        .context("Could not get http client")?
+        .client
+        .clone();
+
+    drop(data);
+
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        for url in urls {
+            let result = with_retries(purge_config.retries, || {
+                purge_url(&http_client, &purge_config, &url)
+            })
+            .await
+            .with_context(|| format!("Could not purge {} from cdn", url));
+            if let Err(err) = result {
+                println!("{:?}", err);
+                report_purge_failure(&ctx, &url, &err).await;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Same as `purge_cdn`, also purging the configured dataset files. Only called once the
+/// changed dataset is served, so the CDN cannot cache the old one again.
+pub async fn purge_dataset_cdn(ctx: &Context, urls: Vec<String>) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let dataset_urls = match &data
+        .get::<Config>()
+        .context("Could not get config")?
+        .cdn_purge
+    {
+        Some(purge_config) => purge_config.dataset_urls.clone(),
+        None => return Ok(()),
+    };
+    drop(data);
+
+    let mut urls = urls;
+    urls.extend(dataset_urls);
+    purge_cdn(ctx, urls).await
+}
+
+async fn purge_url(http_client: &Client, purge_config: &CdnPurge, url: &str) -> anyhow::Result<()> {
+    let mut request = http_client
+        .post(purge_config.url.replace("{url}", url))
+        .timeout(PURGE_TIMEOUT)
+        .body(purge_config.body.replace("{url}", url));
+
+    for (name, value) in &purge_config.headers {
+        request = request.header(name, value.replace("{url}", url));
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "Purge endpoint returned {}: {}",
+            response.status(),
+            response.text().await?
+        );
+    }
+    Ok(())
+}
+
+async fn report_purge_failure(ctx: &Context, url: &str, err: &anyhow::Error) {
+    let data = ctx.data.read().await;
+    let config = match data.get::<Config>() {
+        Some(config) => config,
+        None => return,
+    };
+
+    let result = config
+        .server
+        .log_channel_id
+        .say(
+            &ctx.http,
+            format!("CDN purge failed for <{}>: {:#}", url, err),
+        )
+        .await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+}
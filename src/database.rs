@@ -1,9 +1,8 @@
-use crate::structs::{Blacklist, Collections, Config, Usrbg};
-
 use anyhow::Context;
 use bson::doc;
-use mongodb::sync::Collection;
-use mongodb::{options::FindOneAndUpdateOptions, sync::Client};
+use futures_util::TryStreamExt;
+use mongodb::Collection;
+use mongodb::{options::FindOneAndUpdateOptions, Client};
 use serde::de::DeserializeOwned;
 
 // pub fn create() {}
@@ -11,7 +10,7 @@ use serde::de::DeserializeOwned;
 // pub fn read() {}
 
 // Update to only take entry and retrieve uid by entry - Should I even do it that way?
-pub fn upsert<T>(
+pub async fn upsert<T>(
     collection: &Collection<T>,
     uid: &String,
     entry: T,
@@ -23,29 +22,31 @@ where
         .upsert(Some(true))
         .build();
 
-    collection.find_one_and_update(
-        doc! { "uid": uid },
-        doc! { "$set": bson::to_bson(&entry).unwrap() },
-        Some(options),
-    )
+    collection
+        .find_one_and_update(
+            doc! { "uid": uid },
+            doc! { "$set": bson::to_bson(&entry).unwrap() },
+            Some(options),
+        )
+        .await
+}
+
+pub async fn find_all<T>(collection: &Collection<T>) -> Result<Vec<T>, mongodb::error::Error>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    collection.find(None, None).await?.try_collect().await
 }
 
-pub fn delete<T>(
+pub async fn delete<T>(
     collection: &Collection<T>,
     uid: String,
 ) -> Result<mongodb::results::DeleteResult, mongodb::error::Error> {
-    collection.delete_one(doc! { "uid": uid }, None)
+    collection.delete_one(doc! { "uid": uid }, None).await
 }
 
-pub fn connect_database(config: &Config) -> anyhow::Result<Collections> {
-    let client =
-        Client::with_uri_str(&config.database.url).context("Error connecting to database")?;
-    let db = client.database(&config.database.name);
-    let usrbg_collection = db.collection::<Usrbg>(&config.database.usrbg_collection);
-    let blacklist_collection = db.collection::<Blacklist>(&config.database.blacklist_collection);
-    let collections = Collections {
-        usrbg: usrbg_collection,
-        blacklist: blacklist_collection,
-    };
-    Ok(collections)
+pub async fn connect_raw_client(url: &str) -> anyhow::Result<Client> {
+    Client::with_uri_str(url)
+        .await
+        .context("Error connecting to database")
 }
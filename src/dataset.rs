@@ -0,0 +1,239 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::{bail, Context as AnyhowContext};
+use serenity::client::Context;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cdn::purge_dataset_cdn,
+    s3bucket::public_url,
+    signing::{load_signing_key, sign_dataset},
+    structs::{ChangesSince, Config, DatasetPublisher, Repositories, S3Bucket, UsrbgCache},
+};
+
+/// Called after every change the bot makes to the usrbg collection. Errors are only
+/// logged, the change itself has already been stored.
+pub async fn usrbg_changed(ctx: &Context) {
+    let result = refresh_and_publish_dataset(ctx).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+}
+
+/// Same as `usrbg_changed`, returning whether the cached dataset could be refreshed.
+/// The change is published either way.
+pub async fn refresh_and_publish_dataset(ctx: &Context) -> anyhow::Result<()> {
+    let result = refresh_dataset(ctx).await;
+
+    let data = ctx.data.read().await;
+    let publishing = data
+        .get::<Config>()
+        .is_some_and(|config| config.publish.is_some());
+    match data.get::<DatasetPublisher>() {
+        Some(publisher) => publisher.changed.notify_one(),
+        None => println!("Could not get dataset publisher"),
+    }
+    drop(data);
+
+    // The publisher purges the dataset files after uploading them. Without one they are
+    // served from the cache, which has just been refreshed.
+    if !publishing {
+        let purge_result = purge_dataset_cdn(ctx, vec![]).await;
+        if purge_result.is_err() {
+            println!("{:?}", purge_result);
+        }
+    }
+
+    result
+}
+
+async fn refresh_dataset(ctx: &Context) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    let cache = data
+        .get::<UsrbgCache>()
+        .context("Could not get usrbg cache")?
+        .clone();
+    drop(data);
+
+    load_dataset(&repositories, &cache).await
+}
+
+/// Reloads the cached dataset from the database. The last modified time only moves
+/// forward when the contents actually changed.
+pub async fn load_dataset(repositories: &Repositories, cache: &UsrbgCache) -> anyhow::Result<()> {
+    // A reload that started earlier could otherwise overwrite a newer one's entries
+    let _reload = cache.reload.lock().await;
+
+    // Read before the entries so the entries are at least as new as the revision
+    let revision = repositories.backgrounds.revision().await?;
+    let entries: BTreeMap<_, _> = repositories
+        .backgrounds
+        .all()
+        .await?
+        .into_iter()
+        .map(|entry| (entry.uid.clone(), entry))
+        .collect();
+
+    let etag = hex::encode(Sha256::digest(serde_json::to_vec(&entries)?));
+
+    let mut dataset = cache.dataset.write().await;
+    dataset.revision = revision;
+    if dataset.etag != etag {
+        dataset.entries = entries;
+        dataset.etag = etag;
+        dataset.last_modified = bson::DateTime::now();
+    }
+
+    Ok(())
+}
+
+/// Changes after `since` in the changes-since format
+pub async fn changes_since(
+    repositories: &Repositories,
+    since: i64,
+    limit: i64,
+) -> anyhow::Result<ChangesSince> {
+    let changes = repositories.backgrounds.changes_since(since, limit).await?;
+    // Read after the changes so it is never older than the last change
+    let revision = repositories.backgrounds.revision().await?;
+
+    Ok(ChangesSince {
+        since,
+        revision,
+        changes,
+    })
+}
+
+/// Publishes the dataset files on startup and again whenever the dataset changes.
+/// Changes made in quick succession, e.g. by `~migrate-urls`, are published together.
+pub async fn publish_dataset_changes(ctx: Context) {
+    let data = ctx.data.read().await;
+    let publish_config = data
+        .get::<Config>()
+        .and_then(|config| config.publish.clone());
+    let publisher = data.get::<DatasetPublisher>().cloned();
+    drop(data);
+
+    let (publish_config, publisher) = match (publish_config, publisher) {
+        (Some(publish_config), Some(publisher)) => (publish_config, publisher),
+        _ => return,
+    };
+    let debounce = Duration::from_secs(publish_config.debounce_secs);
+
+    loop {
+        let result = publish_dataset(&ctx).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
+
+        publisher.changed.notified().await;
+        // Wait until no change has been made for a full debounce period
+        while tokio::time::timeout(debounce, publisher.changed.notified())
+            .await
+            .is_ok()
+        {}
+    }
+}
+
+async fn publish_dataset(ctx: &Context) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let publish_config = config
+        .publish
+        .clone()
+        .context("Dataset publishing is not configured")?;
+    let mut bucket = data
+        .get::<S3Bucket>()
+        .context("Could not get bucket")?
+        .bucket
+        .clone();
+    let cache = data
+        .get::<UsrbgCache>()
+        .context("Could not get usrbg cache")?
+        .clone();
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+
+    let mut files = vec![];
+    let dataset = cache.dataset.read().await;
+    let users: BTreeMap<_, _> = dataset
+        .entries
+        .values()
+        .map(|entry| (entry.uid.as_str(), entry.img.as_str()))
+        .collect();
+    files.push((
+        publish_config.json_key.clone(),
+        serde_json::to_vec(&users)?,
+        "application/json",
+    ));
+    if let Some(css_key) = &publish_config.css_key {
+        files.push((
+            css_key.clone(),
+            dataset_css(&users).into_bytes(),
+            "text/css",
+        ));
+    }
+    drop(dataset);
+    if let Some(changes_key) = &publish_config.changes_key {
+        let revision = repositories.backgrounds.revision().await?;
+        let since = (revision - publish_config.changes_retained).max(0);
+        let changes = changes_since(&repositories, since, publish_config.changes_retained).await?;
+        files.push((
+            changes_key.clone(),
+            serde_json::to_vec(&changes)?,
+            "application/json",
+        ));
+    }
+
+    if let Some(signing_key) = &publish_config.signing_key {
+        let signing_key = load_signing_key(signing_key)?;
+        let mut signatures = vec![];
+        for (key, contents, _) in &files {
+            signatures.push((
+                format!("{}.sig", key),
+                serde_json::to_vec(&sign_dataset(&signing_key, contents))?,
+                "application/json",
+            ));
+        }
+        files.extend(signatures);
+    }
+
+    let urls: Vec<String> = files
+        .iter()
+        .map(|(key, _, _)| public_url(config, key))
+        .collect();
+
+    drop(data);
+
+    bucket.add_header("Cache-Control", &publish_config.cache_control);
+    for (key, contents, content_type) in files {
+        let response = bucket
+            .put_object_with_content_type(&key, &contents, content_type)
+            .await?;
+
+        if response.status_code() != 200 {
+            bail!("Error uploading {} to minio", key)
+        }
+    }
+
+    purge_dataset_cdn(ctx, urls).await
+}
+
+/// Classic usrbg stylesheet, one `background-image` rule per user
+fn dataset_css(users: &BTreeMap<&str, &str>) -> String {
+    let mut css = String::new();
+    for (uid, img) in users {
+        let img = img.replace('\\', "\\\\").replace('"', "\\\"");
+        css += &format!(
+            "[data-user-id=\"{}\"] {{ background-image: url(\"{}\"); }}\n",
+            uid, img
+        );
+    }
+    css
+}
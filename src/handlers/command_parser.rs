@@ -0,0 +1,459 @@
+use std::{collections::HashMap, num::NonZeroU64};
+
+use anyhow::bail;
+use serenity::all::UserId;
+
+// Discord user ids have at least this many digits
+const MIN_ID_LENGTH: usize = 17;
+
+/// Which users a text command expects before its other arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Targets {
+    None,
+    One,
+    Many,
+}
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    /// Only usable by members with the auth role
+    pub admin: bool,
+    pub targets: Targets,
+    /// Whether free-form arguments are accepted after the targets
+    pub arguments: bool,
+    pub flags: &'static [&'static str],
+}
+
+// A command may be listed twice, once for members and once for moderators. Moderators get
+// the member version when they give no targets, e.g. `~remove` removes their own background.
+pub static COMMANDS: [CommandSpec; 11] = [
+    CommandSpec {
+        name: "~help",
+        usage: "~help",
+        description: "List the commands you can use",
+        admin: false,
+        targets: Targets::None,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~remove",
+        usage: "~remove",
+        description: "Remove your own background",
+        admin: false,
+        targets: Targets::None,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~remove",
+        usage: "~remove <users...>",
+        description: "Remove the backgrounds of the given users",
+        admin: true,
+        targets: Targets::Many,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~ban",
+        usage: "~ban <users...> [duration] [reason] [--duration=7d] [--reason=\"...\"]",
+        description: "Ban users from submitting backgrounds, permanently if no duration is given",
+        admin: true,
+        targets: Targets::Many,
+        arguments: true,
+        flags: &["duration", "reason"],
+    },
+    CommandSpec {
+        name: "~unban",
+        usage: "~unban <users...>",
+        description: "Allow banned users to submit backgrounds again",
+        admin: true,
+        targets: Targets::Many,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~info",
+        usage: "~info <user>",
+        description: "Show a user's background, ban and requests",
+        admin: true,
+        targets: Targets::One,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~baninfo",
+        usage: "~baninfo <users...>",
+        description: "Show why and until when users are banned",
+        admin: true,
+        targets: Targets::Many,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~audit",
+        usage: "~audit [user:<id>] [mod:<id>] [action:<action>] [page:<n>]",
+        description: "List audit log entries",
+        admin: true,
+        targets: Targets::None,
+        arguments: true,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~audit-export",
+        usage:
+            "~audit-export [user:<id>] [mod:<id>] [action:<action>] [page:<n>] [format:csv|jsonl]",
+        description: "Export matching audit log entries as a file",
+        admin: true,
+        targets: Targets::None,
+        arguments: true,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~migrate-urls",
+        usage: "~migrate-urls",
+        description: "Point stored background urls at the configured public url",
+        admin: true,
+        targets: Targets::None,
+        arguments: false,
+        flags: &[],
+    },
+    CommandSpec {
+        name: "~dedupe",
+        usage: "~dedupe",
+        description: "Remove duplicate background entries",
+        admin: true,
+        targets: Targets::None,
+        arguments: false,
+        flags: &[],
+    },
+];
+
+/// A text command such as `~ban <@123> 80351110224678912 7d "spamming links"`
+#[derive(Debug)]
+pub struct ParsedCommand {
+    pub name: String,
+    /// Mentions or raw ids directly following the command name
+    pub targets: Vec<UserId>,
+    /// Every other word or quoted string
+    pub arguments: Vec<String>,
+    /// `--name=value` options
+    pub flags: HashMap<String, String>,
+}
+
+impl ParsedCommand {
+    pub fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
+    }
+}
+
+struct Token {
+    text: String,
+    /// Quoted tokens are never read as flags or users
+    quoted: bool,
+}
+
+/// Splits a message into words, keeping double quoted strings together
+fn tokenize(content: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut current: Option<Token> = None;
+    let mut in_quotes = false;
+
+    for character in content.chars() {
+        match character {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.get_or_insert(Token {
+                    text: String::new(),
+                    quoted: true,
+                });
+            }
+            character if character.is_whitespace() && !in_quotes => {
+                tokens.extend(current.take());
+            }
+            character => {
+                current
+                    .get_or_insert(Token {
+                        text: String::new(),
+                        quoted: false,
+                    })
+                    .text
+                    .push(character);
+            }
+        }
+    }
+
+    if in_quotes {
+        bail!("Missing closing quote");
+    }
+    tokens.extend(current);
+    Ok(tokens)
+}
+
+/// Parses a `<@id>` or `<@!id>` mention or a raw user id
+pub fn parse_user_id(value: &str) -> Option<UserId> {
+    let id = match value.strip_prefix("<@") {
+        Some(mention) => {
+            let mention = mention.strip_suffix('>')?;
+            mention.strip_prefix('!').unwrap_or(mention)
+        }
+        None => value,
+    };
+    id.parse::<NonZeroU64>().ok().map(UserId::from)
+}
+
+/// Reads a mention or a raw id as a command target. Raw ids must be as long as a Discord id,
+/// so a number that starts the reason, e.g. `~ban <@1> 42 alt accounts`, is not a user.
+fn parse_target(token: &Token) -> Option<UserId> {
+    if token.quoted || (!token.text.starts_with("<@") && token.text.len() < MIN_ID_LENGTH) {
+        return None;
+    }
+    parse_user_id(&token.text)
+}
+
+/// Returns `None` for messages that are not commands
+pub fn parse_command(content: &str) -> anyhow::Result<Option<ParsedCommand>> {
+    if !content.trim_start().starts_with('~') {
+        return Ok(None);
+    }
+
+    let mut tokens = tokenize(content)?.into_iter().peekable();
+    let name = match tokens.next() {
+        Some(token) => token.text,
+        None => return Ok(None),
+    };
+
+    let mut targets = vec![];
+    while let Some(user_id) = tokens.peek().and_then(parse_target) {
+        targets.push(user_id);
+        tokens.next();
+    }
+
+    let mut arguments = vec![];
+    let mut flags = HashMap::new();
+    for token in tokens {
+        match token.text.strip_prefix("--") {
+            Some(flag) if !token.quoted => match flag.split_once('=') {
+                Some((name, value)) => {
+                    flags.insert(name.to_owned(), value.to_owned());
+                }
+                None => bail!("Expected `--{}=<value>`", flag),
+            },
+            _ => arguments.push(token.text),
+        }
+    }
+
+    Ok(Some(ParsedCommand {
+        name,
+        targets,
+        arguments,
+        flags,
+    }))
+}
+
+/// Picks the version of a command the caller can use
+pub fn find_command(name: &str, has_auth: bool, has_targets: bool) -> Option<&'static CommandSpec> {
+    let mut available = COMMANDS
+        .iter()
+        .filter(|spec| spec.name == name && (has_auth || !spec.admin));
+    let first = available.clone().next();
+    available
+        .find(|spec| (spec.targets != Targets::None) == has_targets)
+        .or(first)
+}
+
+impl CommandSpec {
+    /// Checks the command's targets, arguments and flags against this spec
+    pub fn validate(&self, command: &ParsedCommand) -> anyhow::Result<()> {
+        match (self.targets, command.targets.len()) {
+            (Targets::None, 0) | (Targets::One, 1) | (Targets::Many, 1..) => {}
+            (Targets::None, _) => bail!("`{}` does not take any users", self.name),
+            (Targets::One, 0) | (Targets::Many, 0) => {
+                bail!("`{}` needs a user mention or id", self.name)
+            }
+            (Targets::One, _) => bail!("`{}` takes a single user", self.name),
+        }
+
+        if !self.arguments {
+            if let Some(argument) = command.arguments.first() {
+                bail!("Unexpected argument `{}`", argument)
+            }
+        }
+
+        if let Some(flag) = command
+            .flags
+            .keys()
+            .find(|flag| !self.flags.contains(&flag.as_str()))
+        {
+            bail!("Unknown flag `--{}`", flag)
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the commands available at the caller's permission level
+pub fn help_text(has_auth: bool) -> String {
+    let mut lines = vec!["Commands:".to_owned()];
+    lines.extend(
+        COMMANDS
+            .iter()
+            .filter(|spec| has_auth || !spec.admin)
+            .map(|spec| format!("`{}` {}", spec.usage, spec.description)),
+    );
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: u64 = 80351110224678912;
+    const SECOND: u64 = 125227483518861312;
+
+    fn parse(content: &str) -> ParsedCommand {
+        parse_command(content)
+            .expect("command should parse")
+            .expect("message should be a command")
+    }
+
+    fn spec(name: &str, has_targets: bool) -> &'static CommandSpec {
+        find_command(name, true, has_targets).expect("command should exist")
+    }
+
+    #[test]
+    fn ignores_messages_that_are_not_commands() {
+        assert!(parse_command("hello ~ban").unwrap().is_none());
+        assert!(parse_command("").unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_mentions_and_raw_ids_as_targets() {
+        let command = parse(&format!("~ban <@{}> <@!{}> 7d", FIRST, SECOND));
+        assert_eq!(command.name, "~ban");
+        assert_eq!(
+            command.targets,
+            vec![UserId::new(FIRST), UserId::new(SECOND)]
+        );
+        assert_eq!(command.arguments, vec!["7d"]);
+
+        let command = parse(&format!("~unban {} {}", FIRST, SECOND));
+        assert_eq!(
+            command.targets,
+            vec![UserId::new(FIRST), UserId::new(SECOND)]
+        );
+        assert!(command.arguments.is_empty());
+    }
+
+    #[test]
+    fn short_numbers_are_arguments() {
+        let command = parse("~ban <@1> 42 alt accounts");
+        assert_eq!(command.targets, vec![UserId::new(1)]);
+        assert_eq!(command.arguments, vec!["42", "alt", "accounts"]);
+    }
+
+    #[test]
+    fn targets_stop_at_the_first_argument() {
+        let command = parse(&format!("~ban {} spam {}", FIRST, SECOND));
+        assert_eq!(command.targets, vec![UserId::new(FIRST)]);
+        assert_eq!(
+            command.arguments,
+            vec!["spam".to_owned(), SECOND.to_string()]
+        );
+    }
+
+    #[test]
+    fn keeps_quoted_strings_together() {
+        let command = parse(&format!("~ban {} 7d \"spamming  links\" again", FIRST));
+        assert_eq!(command.arguments, vec!["7d", "spamming  links", "again"]);
+    }
+
+    #[test]
+    fn quoted_ids_and_flags_are_arguments() {
+        let command = parse(&format!("~ban \"{}\" \"--reason=x\"", FIRST));
+        assert!(command.targets.is_empty());
+        assert_eq!(
+            command.arguments,
+            vec![FIRST.to_string(), "--reason=x".to_owned()]
+        );
+        assert!(command.flags.is_empty());
+    }
+
+    #[test]
+    fn reads_flags_with_quoted_values() {
+        let command = parse(&format!("~ban {} --duration=2w --reason=\"a b\"", FIRST));
+        assert_eq!(command.flag("duration"), Some("2w"));
+        assert_eq!(command.flag("reason"), Some("a b"));
+        assert!(command.arguments.is_empty());
+    }
+
+    #[test]
+    fn rejects_flags_without_values() {
+        let err = parse_command("~ban 1 --reason").unwrap_err();
+        assert_eq!(err.to_string(), "Expected `--reason=<value>`");
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        let err = parse_command("~ban 1 \"spamming links").unwrap_err();
+        assert_eq!(err.to_string(), "Missing closing quote");
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let command = parse(&format!("~unban {} --reason=x", FIRST));
+        let err = spec("~unban", true).validate(&command).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown flag `--reason`");
+
+        let command = parse(&format!("~ban {} --reason=x", FIRST));
+        assert!(spec("~ban", true).validate(&command).is_ok());
+    }
+
+    #[test]
+    fn checks_target_counts() {
+        let command = parse("~ban spam");
+        let err = spec("~ban", false).validate(&command).unwrap_err();
+        assert_eq!(err.to_string(), "`~ban` needs a user mention or id");
+
+        let command = parse(&format!("~info {} {}", FIRST, SECOND));
+        let err = spec("~info", true).validate(&command).unwrap_err();
+        assert_eq!(err.to_string(), "`~info` takes a single user");
+
+        let command = parse(&format!("~dedupe {}", FIRST));
+        let err = spec("~dedupe", true).validate(&command).unwrap_err();
+        assert_eq!(err.to_string(), "`~dedupe` does not take any users");
+    }
+
+    #[test]
+    fn rejects_arguments_for_commands_without_them() {
+        let command = parse(&format!("~unban {} please", FIRST));
+        let err = spec("~unban", true).validate(&command).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected argument `please`");
+    }
+
+    #[test]
+    fn picks_the_command_for_the_callers_permissions() {
+        assert_eq!(
+            find_command("~remove", false, false).unwrap().usage,
+            "~remove"
+        );
+        assert_eq!(
+            find_command("~remove", true, false).unwrap().usage,
+            "~remove"
+        );
+        assert_eq!(
+            find_command("~remove", true, true).unwrap().usage,
+            "~remove <users...>"
+        );
+        assert!(find_command("~ban", false, true).is_none());
+        assert!(find_command("~nope", true, false).is_none());
+    }
+
+    #[test]
+    fn help_only_lists_available_commands() {
+        assert!(!help_text(false).contains("~ban"));
+        assert!(help_text(true).contains("~ban"));
+    }
+}
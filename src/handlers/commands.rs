@@ -1,138 +1,448 @@
-use anyhow::Context as AnyhowContext;
-use serenity::{client::Context, model::channel::Message};
+use anyhow::{bail, Context as AnyhowContext};
+use serenity::{all::UserId, client::Context, model::channel::Message};
 
 use crate::{
+    audit::{
+        export_audit_entries, format_audit_entry, parse_audit_query, AUDIT_EXPORT_PAGE_SIZE,
+        AUDIT_PAGE_SIZE,
+    },
     auth::HasAuth,
-    database,
-    responses::send_command_reply,
-    s3bucket::delete_image_from_s3_bucket,
-    structs::{Blacklist, Collections},
+    dataset::usrbg_changed,
+    handlers::{
+        command_parser::{find_command, help_text, parse_command, ParsedCommand, COMMANDS},
+        info::user_info_embed,
+    },
+    moderation::{ban_user, parse_duration, remove_background, unban_user},
+    responses::{send_command_reply, send_command_reply_with_embed, send_command_reply_with_file},
+    s3bucket::{public_url, referenced_object_key},
+    structs::{AuditAction, Blacklist, Config, Repositories, Usrbg},
 };
 
 pub async fn handle_commands(ctx: Context, msg: Message) {
-    let message_content = msg.content.clone();
-    let mut message_words = message_content.split_whitespace();
-    let command = message_words.next();
-    match command {
-        Some(command) => {
-            let command_argument = message_words.next();
-
-            let result = handle_command_auth_level(ctx, msg, command, command_argument).await;
-            if result.is_err() {
-                println!("{:?}", result);
-            }
-        }
-        None => {}
+    let result = handle_command(ctx, msg).await;
+    if result.is_err() {
+        println!("{:?}", result);
     }
 }
 
-pub async fn handle_command_auth_level(
-    ctx: Context,
-    msg: Message,
-    command: &str,
-    command_argument: Option<&str>,
-) -> anyhow::Result<()> {
+async fn handle_command(ctx: Context, msg: Message) -> anyhow::Result<()> {
+    let command = match parse_command(&msg.content) {
+        Ok(Some(command)) => command,
+        Ok(None) => return Ok(()),
+        Err(err) => {
+            send_command_reply(msg, ctx, &format!("{:#}, see `~help`", err)).await?;
+            return Ok(());
+        }
+    };
+
     let has_auth = msg
         .member
         .as_ref()
         .context("could not get auth")?
         .has_auth(&ctx)
         .await?;
-    if has_auth && command_argument.is_some() {
-        handle_admin_commands(ctx, msg, command, command_argument).await?;
+
+    let spec = match find_command(&command.name, has_auth, !command.targets.is_empty()) {
+        Some(spec) => spec,
+        None => {
+            let response = if COMMANDS.iter().any(|spec| spec.name == command.name) {
+                format!("You are not allowed to use `{}`", command.name)
+            } else {
+                format!("Unknown command `{}`, see `~help`", command.name)
+            };
+            send_command_reply(msg, ctx, &response).await?;
+            return Ok(());
+        }
+    };
+
+    match spec.validate(&command) {
+        Ok(()) => {}
+        Err(err) => {
+            let response = format!("{:#}\nUsage: `{}`", err, spec.usage);
+            send_command_reply(msg, ctx, &response).await?;
+            return Ok(());
+        }
+    }
+
+    if spec.admin {
+        handle_admin_commands(ctx, msg, command).await
     } else {
-        handle_user_commands(ctx, msg, command).await?;
+        handle_user_commands(ctx, msg, command, has_auth).await
     }
-    Ok(())
 }
 
 pub async fn handle_admin_commands(
     ctx: Context,
     msg: Message,
-    command: &str,
-    command_argument: Option<&str>,
+    command: ParsedCommand,
 ) -> anyhow::Result<()> {
-    let user_id = match command_argument {
-        Some(user_id) => user_id,
-        None => "",
-    };
-
-    let valid_user_id = user_id.trim().parse::<u64>().is_ok();
-
-    if valid_user_id {
-        let data = ctx.data.read().await;
-        let collections = data
-            .get::<Collections>()
-            .context("Could not get collections")?;
-
-        match command {
-            "~remove" => {
-                let result = database::delete(&collections.usrbg, user_id.to_string());
-                drop(data);
-                match result {
-                    Ok(_) => {
-                        send_command_reply(msg, ctx, "usrbg removed").await?;
-                    }
-                    Err(_) => {
-                        send_command_reply(msg, ctx, "failed to remove usrbg").await?;
+    match command.name.as_str() {
+        "~migrate-urls" => {
+            let result = migrate_public_urls(&ctx).await;
+            match result {
+                Ok(updated) => {
+                    if updated > 0 {
+                        usrbg_changed(&ctx).await;
                     }
+                    send_command_reply(msg, ctx, &format!("rewrote {} usrbg urls", updated))
+                        .await?;
+                }
+                Err(err) => {
+                    send_command_reply(msg, ctx, "failed to rewrite usrbg urls").await?;
+                    return Err(err);
                 }
             }
-            "~ban" => {
-                let entry = Blacklist {
-                    uid: user_id.to_owned(),
-                };
-                let result = database::upsert(&collections.blacklist, &user_id.to_string(), entry);
-                drop(data);
-                match result {
-                    Ok(_) => {
-                        send_command_reply(msg, ctx, "banned user").await?;
-                    }
-                    Err(_) => {
-                        send_command_reply(msg, ctx, "failed to ban user").await?;
+        }
+        "~dedupe" => {
+            let data = ctx.data.read().await;
+            let repositories = data
+                .get::<Repositories>()
+                .context("Could not get repositories")?
+                .clone();
+            drop(data);
+
+            let result = repositories.schema.resolve_duplicates().await;
+            match result {
+                Ok(deleted) => {
+                    if deleted > 0 {
+                        usrbg_changed(&ctx).await;
                     }
+                    send_command_reply(msg, ctx, &format!("removed {} duplicate entries", deleted))
+                        .await?;
+                }
+                Err(err) => {
+                    send_command_reply(msg, ctx, "failed to remove duplicate entries").await?;
+                    return Err(err);
+                }
+            }
+        }
+        "~audit" | "~audit-export" => {
+            let query_words: Vec<&str> = command.arguments.iter().map(String::as_str).collect();
+            handle_audit_command(ctx, msg, &command.name, &query_words).await?;
+        }
+        "~info" => {
+            let result = user_info_embed(&ctx, &command.targets[0].to_string()).await;
+            match result {
+                Ok(embed) => {
+                    send_command_reply_with_embed(msg, ctx, embed).await?;
+                }
+                Err(err) => {
+                    send_command_reply(msg, ctx, "failed to look up user").await?;
+                    return Err(err);
                 }
             }
-            "~unban" => {
-                let result = database::delete(&collections.blacklist, user_id.to_string());
-                drop(data);
-                match result {
-                    Ok(_) => {
-                        send_command_reply(msg, ctx, "unbanned user").await?;
+        }
+        "~ban" => {
+            // ~ban <users...> [duration] [reason], or the same as --duration and --reason flags
+            let mut arguments = command.arguments.iter().peekable();
+            let duration = match command.flag("duration") {
+                Some(duration) => match parse_duration(duration) {
+                    Some(duration) => Some(duration),
+                    None => {
+                        let response = format!(
+                            "Invalid duration `{}`, expected e.g. 30m, 12h, 7d or 2w",
+                            duration
+                        );
+                        send_command_reply(msg, ctx, &response).await?;
+                        return Ok(());
                     }
-                    Err(_) => {
-                        send_command_reply(msg, ctx, "failed to unban user").await?;
+                },
+                None => {
+                    let duration = arguments
+                        .peek()
+                        .and_then(|argument| parse_duration(argument));
+                    if duration.is_some() {
+                        arguments.next();
                     }
+                    duration
                 }
+            };
+            let reason = match command.flag("reason") {
+                Some(reason) => reason.to_owned(),
+                None => arguments.map(String::as_str).collect::<Vec<_>>().join(" "),
+            };
+            let reason = if reason.is_empty() {
+                None
+            } else {
+                Some(reason)
+            };
+
+            let mut responses = vec![];
+            for user_id in &command.targets {
+                let result = ban_user(
+                    &ctx,
+                    &user_id.to_string(),
+                    msg.author.id,
+                    duration,
+                    reason.clone(),
+                )
+                .await;
+                let response = match result {
+                    Ok(entry) => ban_reply(&entry),
+                    Err(err) => {
+                        println!("{:?}", err);
+                        "failed to ban user".to_owned()
+                    }
+                };
+                responses.push((*user_id, response));
+            }
+            send_command_reply(msg, ctx, &target_responses(&responses)).await?;
+        }
+        "~remove" | "~unban" | "~baninfo" => {
+            let mut responses = vec![];
+            for user_id in &command.targets {
+                let response = handle_target_command(&ctx, &msg, &command.name, *user_id).await;
+                responses.push((*user_id, response));
             }
-            &_ => {}
+            send_command_reply(msg, ctx, &target_responses(&responses)).await?;
         }
+        name => bail!("No handler for command {}", name),
     }
     Ok(())
 }
 
-pub async fn handle_user_commands(ctx: Context, msg: Message, command: &str) -> anyhow::Result<()> {
+/// Runs a command that acts on each target separately and describes the outcome
+async fn handle_target_command(
+    ctx: &Context,
+    msg: &Message,
+    command: &str,
+    user_id: UserId,
+) -> String {
+    let uid = user_id.to_string();
     match command {
         "~remove" => {
+            let result =
+                remove_background(ctx, &uid, msg.author.id, AuditAction::AdminRemove).await;
+            match result {
+                Ok(removal) if removal.found => removal.summary(),
+                Ok(_) => "user does not have a usrbg".to_owned(),
+                Err(err) => {
+                    println!("{:?}", err);
+                    "failed to remove usrbg".to_owned()
+                }
+            }
+        }
+        "~unban" => {
+            let result = unban_user(ctx, &uid, msg.author.id).await;
+            match result {
+                Ok(true) => "unbanned user".to_owned(),
+                Ok(false) => "user is not banned".to_owned(),
+                Err(err) => {
+                    println!("{:?}", err);
+                    "failed to unban user".to_owned()
+                }
+            }
+        }
+        "~baninfo" => {
             let data = ctx.data.read().await;
-            let collections = data
-                .get::<Collections>()
-                .context("Could not get collections")?;
-
-            let result = database::delete(&collections.usrbg, msg.author.id.to_string());
+            let repositories = match data.get::<Repositories>() {
+                Some(repositories) => repositories.clone(),
+                None => return "failed to look up ban".to_owned(),
+            };
             drop(data);
 
-            let result_2 = delete_image_from_s3_bucket(&ctx, msg.author.id.to_string()).await;
+            let result = repositories.blacklist.get(&uid).await;
+            match result {
+                Ok(Some(entry)) if entry.is_active() => describe_ban(&entry),
+                Ok(_) => "user is not banned".to_owned(),
+                Err(err) => {
+                    println!("{:?}", err);
+                    "failed to look up ban".to_owned()
+                }
+            }
+        }
+        _ => format!("No handler for command {}", command),
+    }
+}
+
+/// Joins the outcome for every target, naming the user when there are several
+fn target_responses(responses: &[(UserId, String)]) -> String {
+    match responses {
+        [(_, response)] => response.clone(),
+        responses => responses
+            .iter()
+            .map(|(user_id, response)| format!("<@{}>: {}", user_id, response))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
 
-            if result.is_ok() {
-                send_command_reply(msg, ctx, "usrbg removed").await?;
-            } else {
-                send_command_reply(msg, ctx, "failed to remove usrbg").await?;
-                result?;
-                result_2?;
+pub async fn handle_user_commands(
+    ctx: Context,
+    msg: Message,
+    command: ParsedCommand,
+    has_auth: bool,
+) -> anyhow::Result<()> {
+    match command.name.as_str() {
+        "~help" => {
+            send_command_reply(msg, ctx, &help_text(has_auth)).await?;
+        }
+        "~remove" => {
+            let uid = msg.author.id.to_string();
+            let result =
+                remove_background(&ctx, &uid, msg.author.id, AuditAction::SelfRemove).await;
+
+            match result {
+                Ok(removal) if removal.found => {
+                    send_command_reply(msg, ctx, &removal.summary()).await?
+                }
+                Ok(_) => send_command_reply(msg, ctx, "you do not have a usrbg").await?,
+                Err(err) => {
+                    send_command_reply(msg, ctx, "failed to remove usrbg").await?;
+                    return Err(err);
+                }
             }
         }
-        &_ => {}
+        name => bail!("No handler for command {}", name),
+    }
+    Ok(())
+}
+
+/// `~audit [filters]` lists one page of matching audit log entries, `~audit-export [filters]`
+/// sends a much larger page of them as a file
+async fn handle_audit_command(
+    ctx: Context,
+    msg: Message,
+    command: &str,
+    query_words: &[&str],
+) -> anyhow::Result<()> {
+    let query = match parse_audit_query(query_words) {
+        Ok(query) => query,
+        Err(err) => {
+            send_command_reply(msg, ctx, &format!("{:#}", err)).await?;
+            return Ok(());
+        }
+    };
+
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    if command == "~audit-export" {
+        // Fetch one extra entry to know whether there is a next page
+        let entries = repositories
+            .audit_log
+            .search(
+                &query.filter,
+                query.page * AUDIT_EXPORT_PAGE_SIZE,
+                Some(AUDIT_EXPORT_PAGE_SIZE + 1),
+            )
+            .await?;
+        let page_entries = &entries[..entries.len().min(AUDIT_EXPORT_PAGE_SIZE as usize)];
+        let export = export_audit_entries(page_entries, query.format)?;
+
+        let mut response = format!("exported {} audit log entries", export.exported);
+        if export.exported < page_entries.len() {
+            response += &format!(
+                ", the file size limit was reached before the other {} entries of this page, narrow the filters to export them",
+                page_entries.len() - export.exported
+            );
+        } else if entries.len() > page_entries.len() {
+            response += &format!(", use `page:{}` for more", query.page + 2);
+        }
+        send_command_reply_with_file(msg, ctx, &response, export.contents, export.filename).await?;
+        return Ok(());
     }
+
+    // Fetch one extra entry to know whether there is a next page
+    let mut entries = repositories
+        .audit_log
+        .search(
+            &query.filter,
+            query.page * AUDIT_PAGE_SIZE,
+            Some(AUDIT_PAGE_SIZE + 1),
+        )
+        .await?;
+    let has_next_page = entries.len() as u64 > AUDIT_PAGE_SIZE;
+    entries.truncate(AUDIT_PAGE_SIZE as usize);
+
+    if entries.is_empty() {
+        send_command_reply(msg, ctx, "no matching audit log entries").await?;
+        return Ok(());
+    }
+
+    let mut response: Vec<String> = entries.iter().map(format_audit_entry).collect();
+    response.push(format!(
+        "Page {}{}",
+        query.page + 1,
+        if has_next_page {
+            format!(", use `page:{}` for more", query.page + 2)
+        } else {
+            String::new()
+        }
+    ));
+    send_command_reply(msg, ctx, &response.join("\n")).await?;
+
     Ok(())
 }
+
+/// Rewrites the stored image URL of every background kept in our bucket so it
+/// matches the currently configured public URL.
+async fn migrate_public_urls(ctx: &Context) -> anyhow::Result<usize> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    let entries = repositories.backgrounds.all().await?;
+
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let rewritten: Vec<Usrbg> = entries
+        .into_iter()
+        .filter_map(|mut entry| {
+            // Entries that do not point at our bucket (e.g. legacy imgur links) are left alone
+            let key = referenced_object_key(config, &entry)?;
+            let url = public_url(config, &key);
+            if entry.img == url {
+                return None;
+            }
+            entry.img = url;
+            Some(entry)
+        })
+        .collect();
+    drop(data);
+
+    let updated = rewritten.len();
+    for entry in rewritten {
+        repositories.backgrounds.upsert(entry).await?;
+    }
+
+    Ok(updated)
+}
+
+pub fn ban_reply(entry: &Blacklist) -> String {
+    match entry.expires_at {
+        Some(expires_at) => format!(
+            "banned user until <t:{}:f>",
+            expires_at.timestamp_millis() / 1000
+        ),
+        None => "banned user".to_owned(),
+    }
+}
+
+pub fn describe_ban(entry: &Blacklist) -> String {
+    let mut description = format!("<@{}> is banned", entry.uid);
+    if let Some(moderator) = &entry.moderator {
+        description += &format!(" by <@{}>", moderator);
+    }
+    if let Some(created_at) = entry.created_at {
+        description += &format!(" since <t:{}:f>", created_at.timestamp_millis() / 1000);
+    }
+    match entry.expires_at {
+        Some(expires_at) => {
+            description += &format!(" until <t:{}:f>", expires_at.timestamp_millis() / 1000);
+        }
+        None => description += " permanently",
+    }
+    match &entry.reason {
+        Some(reason) => description += &format!("\nReason: {}", reason),
+        None => description += "\nNo reason given",
+    }
+    description
+}
@@ -5,14 +5,15 @@ use serenity::{
     builder::CreateInteractionResponse, client::Context, model::application::ComponentInteraction,
 };
 
-use crate::responses::delete_user_request;
-use crate::structs::Collections;
+use crate::moderation::{approve_request, deny_request, store_cancellation};
+use crate::responses::{delete_user_request, get_request_message_id};
+use crate::structs::Repositories;
 use crate::{
     auth::HasAuth,
-    database,
-    responses::{edit_request, send_ephemeral_interaction_reply},
-    s3bucket::upload_image_to_s3bucket,
-    structs::Usrbg,
+    responses::{
+        edit_request, send_ephemeral_interaction_followup_reply, send_ephemeral_interaction_reply,
+    },
+    s3bucket::delete_staged_image,
 };
 
 pub async fn handle_component_interaction(
@@ -33,8 +34,6 @@ pub async fn handle_component_interaction(
         .context("Could not get first embed")?
         .clone();
 
-    let embed_link = embed.url.clone();
-
     let image_url = embed
         .thumbnail
         .clone()
@@ -44,17 +43,16 @@ pub async fn handle_component_interaction(
     let mut uid: Option<String> = None;
 
     for field in &embed.fields {
-        match field.name.as_str() {
-            "UID" => {
-                uid = Some(field.value.clone());
-                break;
-            }
-            _ => {}
+        if field.name.as_str() == "UID" {
+            uid = Some(field.value.clone());
+            break;
         }
     }
 
     let uid: String = uid.context("Could not parse uid from embed")?;
 
+    let request_message_id = get_request_message_id(&embed)?;
+
     match component_interaction.data.custom_id.as_str() {
         "Approve" => {
             if has_auth {
@@ -63,50 +61,23 @@ pub async fn handle_component_interaction(
                     .await
                     .context("Could not acknowledge component interaction")?;
 
-                edit_request(
-                    &ctx,
-                    &mut component_interaction.message,
-                    "Uploading...",
-                    Some(&image_url),
-                    embed_link.as_deref(),
-                    false,
-                )
-                .await
-                .context("Could not update message to show loading state")?;
-
-                let s3bucket_url = upload_image_to_s3bucket(&ctx, image_url.clone(), uid.clone())
-                    .await
-                    .context("Could not upload image to s3bucket")?;
-
-                let entry = Usrbg {
-                    uid: uid.clone(),
-                    img: s3bucket_url.clone(),
-                };
-
-                let data = ctx.data.read().await;
-                let collections = data
-                    .get::<Collections>()
-                    .context("Could not get collections")?;
-
-                database::upsert(&collections.usrbg, &uid, entry)
-                    .context("Could not upsert into database")?;
-
-                edit_request(
+                let img = approve_request(
                     &ctx,
                     &mut component_interaction.message,
-                    "Request Approved",
-                    Some(&s3bucket_url),
-                    None,
-                    false,
+                    &uid,
+                    &image_url,
+                    request_message_id,
+                    component_interaction.user.id,
                 )
-                .await
-                .context("could not edit request message")?;
-
-                drop(data);
-
-                delete_user_request(&ctx, &embed)
-                    .await
-                    .context("Could not delete original request")?;
+                .await?;
+                if img.is_none() {
+                    send_ephemeral_interaction_followup_reply(
+                        &ctx,
+                        component_interaction,
+                        "Request has already been handled",
+                    )
+                    .await?;
+                }
             } else {
                 send_ephemeral_interaction_reply(
                     &ctx,
@@ -124,19 +95,22 @@ pub async fn handle_component_interaction(
                     .await
                     .context("Could not acknowledge component interaction")?;
 
-                edit_request(
+                let denied = deny_request(
                     &ctx,
                     &mut component_interaction.message,
-                    "Request Denied",
-                    None,
-                    None,
-                    false,
+                    &uid,
+                    request_message_id,
+                    component_interaction.user.id,
                 )
-                .await
-                .context("Could not edit request message")?;
-                delete_user_request(&ctx, &embed)
-                    .await
-                    .context("Could not delete original request")?;
+                .await?;
+                if !denied {
+                    send_ephemeral_interaction_followup_reply(
+                        &ctx,
+                        component_interaction,
+                        "Request has already been handled",
+                    )
+                    .await?;
+                }
             } else {
                 send_ephemeral_interaction_reply(
                     &ctx,
@@ -165,6 +139,23 @@ pub async fn handle_component_interaction(
                 .await
                 .context("Could not edit request message")?;
 
+                let data = ctx.data.read().await;
+                let repositories = data
+                    .get::<Repositories>()
+                    .context("Could not get repositories")?
+                    .clone();
+                drop(data);
+
+                let result = store_cancellation(&repositories, &uid, request_message_id).await;
+                if result.is_err() {
+                    println!("{:?}", result);
+                }
+
+                let result = delete_staged_image(&ctx, request_message_id).await;
+                if result.is_err() {
+                    println!("{:?}", result);
+                }
+
                 delete_user_request(&ctx, &embed)
                     .await
                     .context("Could not delete original request")?;
@@ -0,0 +1,133 @@
+use anyhow::Context as AnyhowContext;
+use serenity::{all::MessageId, builder::CreateEmbed, client::Context};
+
+use crate::{
+    handlers::commands::describe_ban,
+    structs::{BackgroundRequest, Config, Repositories, RequestStatus},
+};
+
+// Embed fields are limited to 1024 characters, which fits about this many history lines
+const INFO_HISTORY_LENGTH: u64 = 6;
+
+/// Everything moderators want to know about a user: their background, ban and requests
+pub async fn user_info_embed(ctx: &Context, uid: &str) -> anyhow::Result<CreateEmbed> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    let config = data.get::<Config>().context("Could not get config")?;
+    let guild_id = config.server.guild_id;
+    let log_channel_id = config.server.log_channel_id;
+    drop(data);
+
+    let background = repositories.backgrounds.get(uid).await?;
+    let ban = repositories.blacklist.get(uid).await?;
+    let requests = repositories
+        .requests
+        // One more in case the newest request is still pending
+        .for_user(uid, Some(INFO_HISTORY_LENGTH + 1))
+        .await?;
+
+    let log_message_link = |request: &BackgroundRequest| {
+        request
+            .log_message_id
+            .parse::<u64>()
+            .map(|log_message_id| {
+                MessageId::new(log_message_id).link(log_channel_id, Some(guild_id))
+            })
+            .ok()
+    };
+
+    let mut embed = CreateEmbed::new()
+        .title("usrbg info")
+        .description(format!("<@{}> ({})", uid, uid));
+
+    match &background {
+        Some(background) => {
+            let approved = match (&background.approved_by, background.approved_at) {
+                (Some(approved_by), Some(approved_at)) => format!(
+                    "<t:{}:f> by <@{}>",
+                    approved_at.timestamp_millis() / 1000,
+                    approved_by
+                ),
+                (None, Some(approved_at)) => {
+                    format!("<t:{}:f>", approved_at.timestamp_millis() / 1000)
+                }
+                (Some(approved_by), None) => format!("by <@{}>", approved_by),
+                (None, None) => "unknown".to_owned(),
+            };
+            embed = embed
+                .field("Background", format!("<{}>", background.img), false)
+                .field("Approved", approved, false)
+                .thumbnail(&background.img);
+        }
+        None => embed = embed.field("Background", "none", false),
+    }
+
+    match &ban {
+        Some(ban) if ban.is_active() => embed = embed.field("Ban", describe_ban(ban), false),
+        _ => embed = embed.field("Ban", "not banned", false),
+    }
+
+    let pending = requests
+        .iter()
+        .find(|request| request.status == RequestStatus::Pending);
+    match pending {
+        Some(pending) => {
+            let mut description = format!(
+                "submitted <t:{}:R>",
+                pending.created_at.timestamp_millis() / 1000
+            );
+            if let Some(link) = log_message_link(pending) {
+                description += &format!(", [log message]({})", link)
+            }
+            embed = embed.field("Pending request", description, false);
+        }
+        None => embed = embed.field("Pending request", "none", false),
+    }
+
+    let history: Vec<String> = requests
+        .iter()
+        .filter(|request| request.status != RequestStatus::Pending)
+        .take(INFO_HISTORY_LENGTH as usize)
+        .map(|request| {
+            let mut line = format!(
+                "<t:{}:d> {}",
+                request.created_at.timestamp_millis() / 1000,
+                status_name(request.status)
+            );
+            match &request.decided_by {
+                Some(decided_by) if *decided_by != request.uid => {
+                    line += &format!(" by <@{}>", decided_by)
+                }
+                _ => {}
+            }
+            if let Some(link) = log_message_link(request) {
+                line += &format!(" ([log]({}))", link)
+            }
+            line
+        })
+        .collect();
+    embed = embed.field(
+        "Past requests",
+        if history.is_empty() {
+            "none".to_owned()
+        } else {
+            history.join("\n")
+        },
+        false,
+    );
+
+    Ok(embed)
+}
+
+fn status_name(status: RequestStatus) -> &'static str {
+    match status {
+        RequestStatus::Pending => "pending",
+        RequestStatus::Approved => "approved",
+        RequestStatus::Denied => "denied",
+        RequestStatus::Cancelled => "cancelled",
+        RequestStatus::Expired => "expired",
+    }
+}
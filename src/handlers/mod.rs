@@ -1,3 +1,6 @@
+pub(crate) mod command_parser;
 pub(crate) mod commands;
 pub(crate) mod components;
+pub(crate) mod info;
 pub(crate) mod requests;
+pub(crate) mod slash_commands;
@@ -1,23 +1,39 @@
+use std::time::Duration;
+
 use anyhow::{bail, Context as AnyhowContext};
-use serenity::{client::Context, model::channel::Message};
+use serenity::{
+    all::{MessageId, UserId},
+    client::Context,
+    model::channel::Message,
+};
 
 use crate::{
     auth::{HasAuth, IsBlacklisted},
-    responses::{create_request_log_message, edit_request},
-    structs::{Config, PendingRequestMidStore, PendingRequestUidStore},
+    metrics::{RejectionReason, METRICS},
+    moderation::store_cancellation,
+    responses::{create_request_log_message, delete_user_request, edit_request},
+    s3bucket::{delete_staged_image, stage_image, STAGING_PREFIX},
+    structs::{BackgroundRequest, Config, Repositories, RequestStatus, S3Bucket, WebhookEvent},
+    webhooks::{emit_webhook_event, WebhookEventData},
 };
 
+// How often staged request images are checked for expiry, in seconds
+const EXPIRY_CHECK_INTERVAL: u64 = 60 * 60;
+
 pub async fn handle_user_request(ctx: Context, msg: Message) -> anyhow::Result<()> {
     if msg.author.is_blacklisted(&ctx).await? {
+        METRICS.request_rejected(RejectionReason::Blacklisted);
         msg.delete(&ctx.http).await?;
         bail!("User is blacklisted");
     };
 
     let message_attachment = msg.attachments.first();
+    let has_auth = msg.author.has_auth(&ctx).await?;
 
     // Check to see if attachment exists
 
-    if message_attachment.is_none() && !msg.author.has_auth(&ctx).await? {
+    if message_attachment.is_none() && !has_auth {
+        METRICS.request_rejected(RejectionReason::NoAttachment);
         msg.delete(&ctx.http).await?;
         bail!("No message attachment")
     }
@@ -26,16 +42,17 @@ pub async fn handle_user_request(ctx: Context, msg: Message) -> anyhow::Result<(
 
     // Check to make sure image is under size limit
 
-    if message_attachment.size > 10000000 {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+
+    if message_attachment.size as u64 > config.settings.max_image_size {
+        METRICS.request_rejected(RejectionReason::TooLarge);
         msg.delete(&ctx.http).await?;
         bail!("File size too large");
     }
 
     // Check for valid image type
 
-    let data = ctx.data.read().await;
-    let config = data.get::<Config>().context("Could not get config")?;
-
     let attachment_content_type = &message_attachment
         .content_type
         .as_ref()
@@ -45,77 +62,273 @@ pub async fn handle_user_request(ctx: Context, msg: Message) -> anyhow::Result<(
         .settings
         .image_types
         .contains(&attachment_content_type.to_string())
-        && !msg.author.has_auth(&ctx).await?
+        && !has_auth
     {
+        METRICS.request_rejected(RejectionReason::BadType);
         msg.delete(&ctx.http).await?;
         bail!("Invalid file type");
     }
 
     drop(data);
 
+    // Copy the attachment into the bucket, Discord attachment URLs expire before old requests are handled.
+    // Done before cancelling an existing request, so the user keeps it if this fails.
+
+    let staged = stage_image(&ctx, message_attachment.url.clone(), msg.id, has_auth).await;
+    let (staged_image_url, image) = match staged {
+        Ok(staged) => staged,
+        Err(err) => {
+            METRICS.request_rejected(RejectionReason::StagingFailed);
+            msg.delete(&ctx.http).await?;
+            return Err(err.context("Could not stage request image"));
+        }
+    };
+
     // check if user has an existing request, if so, cancel it first
 
-    let mut data = ctx.data.write().await;
-    let pending_request_uid_store = data
-        .get_mut::<PendingRequestUidStore>()
-        .context("Could not get pending request store")?;
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    let log_channel_id = data
+        .get::<Config>()
+        .context("Could not get config")?
+        .server
+        .log_channel_id;
+    drop(data);
+
+    let existing_request = repositories
+        .requests
+        .pending_for_user(&msg.author.id.to_string())
+        .await?;
 
-    let message_id = pending_request_uid_store.remove(&msg.author.id);
+    if let Some(existing_request) = existing_request {
+        let request_message_id = MessageId::new(existing_request.request_message_id.parse()?);
 
-    let pending_request_mid_store = data
-        .get_mut::<PendingRequestMidStore>()
-        .context("Could not get pending request store")?;
+        let result = store_cancellation(
+            &repositories,
+            &msg.author.id.to_string(),
+            request_message_id,
+        )
+        .await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
 
-    pending_request_mid_store.remove(&msg.id);
+        let result = delete_staged_image(&ctx, request_message_id).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
 
-    let config = data.get::<Config>().context("Could not get config")?;
+        let existing_request = log_channel_id
+            .message(&ctx.http, existing_request.log_message_id.parse::<u64>()?)
+            .await;
 
-    match message_id {
-        Some(message_id) => {
-            let existing_request = config
-                .server
-                .log_channel_id
-                .message(&ctx.http, message_id)
-                .await;
-
-            match existing_request {
-                Ok(mut existing_request) => {
-                    let result = edit_request(
-                        &ctx,
-                        &mut existing_request,
-                        "Request Cancelled",
-                        None,
-                        None,
-                        false,
-                    )
-                    .await
-                    .context("Could not edit request message");
-                    if result.is_err() {
-                        println!("{:?}", result);
-                    }
-                }
-                Err(_) => {}
+        if let Ok(mut existing_request) = existing_request {
+            let result = edit_request(
+                &ctx,
+                &mut existing_request,
+                "Request Cancelled",
+                None,
+                None,
+                false,
+            )
+            .await
+            .context("Could not edit request message");
+            if result.is_err() {
+                println!("{:?}", result);
             }
         }
-        None => {}
     }
 
+    let created_message_id = create_request_log_message(&ctx, &msg, &staged_image_url).await?; // Add error handling here (log to channel?)
+
+    repositories
+        .requests
+        .create(BackgroundRequest {
+            uid: msg.author.id.to_string(),
+            request_message_id: msg.id.to_string(),
+            log_message_id: created_message_id.to_string(),
+            image_url: staged_image_url.clone(),
+            image,
+            status: RequestStatus::Pending,
+            created_at: bson::DateTime::now(),
+            decided_at: None,
+            decided_by: None,
+        })
+        .await
+        .context("Could not store request")?;
+    METRICS.request_submitted();
+
+    let mut event_data = WebhookEventData::new(msg.author.id);
+    event_data.request_message_id = Some(msg.id.to_string());
+    event_data.image_url = Some(staged_image_url);
+    emit_webhook_event(&ctx, WebhookEvent::RequestCreated, event_data).await;
+
+    Ok(())
+}
+
+/// Marks a pending request as handled. Errors are only logged, the request has already
+/// been handled in Discord by the time this is called.
+pub async fn resolve_request(
+    ctx: &Context,
+    request_message_id: MessageId,
+    status: RequestStatus,
+    decided_by: Option<UserId>,
+) -> Option<BackgroundRequest> {
+    let data = ctx.data.read().await;
+    let repositories = data.get::<Repositories>()?.clone();
     drop(data);
 
-    let created_message_id = create_request_log_message(&ctx, &msg).await?; // Add error handling here (log to channel?)
+    let result = repositories
+        .requests
+        .resolve(
+            &request_message_id.to_string(),
+            status,
+            decided_by.map(|decided_by| decided_by.to_string()),
+        )
+        .await;
+
+    match result {
+        Ok(Some(request)) => {
+            METRICS.request_resolved(&request);
+            Some(request)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            println!("{:?}", err);
+            None
+        }
+    }
+}
+
+pub async fn expire_pending_requests(ctx: Context) {
+    loop {
+        let result = expire_stale_requests(&ctx).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
+        tokio::time::sleep(Duration::from_secs(EXPIRY_CHECK_INTERVAL)).await;
+    }
+}
+
+async fn expire_stale_requests(ctx: &Context) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let bucket = &data
+        .get::<S3Bucket>()
+        .context("Could not get bucket")?
+        .bucket;
+
+    let cutoff = bson::DateTime::now().timestamp_millis()
+        - (config.settings.pending_request_expiry_hours * 60 * 60 * 1000) as i64;
+
+    let list_results = bucket
+        .list(STAGING_PREFIX.to_owned(), None)
+        .await
+        .context("Could not list staged images")?;
 
-    // Add new request to local store
+    drop(data);
+
+    for object in list_results.into_iter().flat_map(|result| result.contents) {
+        let last_modified = match bson::DateTime::parse_rfc3339_str(&object.last_modified) {
+            Ok(last_modified) => last_modified,
+            Err(err) => {
+                println!(
+                    "Could not parse modification time of staged image {}: {:?}",
+                    object.key, err
+                );
+                continue;
+            }
+        };
+        if last_modified.timestamp_millis() > cutoff {
+            continue;
+        }
 
-    let mut data = ctx.data.write().await;
-    let pending_request_store = data
-        .get_mut::<PendingRequestUidStore>()
-        .context("Could not get pending request store")?;
-    pending_request_store.insert(msg.author.id, created_message_id);
+        let request_message_id = match object.key[STAGING_PREFIX.len()..].parse::<u64>() {
+            Ok(request_message_id) => MessageId::new(request_message_id),
+            Err(_) => continue,
+        };
 
-    let pending_request_mid_store = data
-        .get_mut::<PendingRequestMidStore>()
-        .context("Could not get pending request store")?;
-    pending_request_mid_store.insert(msg.id, created_message_id);
+        let result = expire_request(ctx, request_message_id).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
+    }
 
     Ok(())
 }
+
+async fn expire_request(ctx: &Context, request_message_id: MessageId) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let log_channel_id = config.server.log_channel_id;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    let request = resolve_request(ctx, request_message_id, RequestStatus::Expired, None).await;
+
+    if request.is_none() {
+        let recent_cutoff =
+            bson::DateTime::now().timestamp_millis() - (EXPIRY_CHECK_INTERVAL * 1000) as i64;
+        match repositories
+            .requests
+            .get(&request_message_id.to_string())
+            .await?
+        {
+            // Without a record there is no log message to mark as expired and moderators can
+            // still approve the request, so the image is kept until one of them handles it
+            None => {
+                println!(
+                    "Staged image of request {} has no request record, keeping it",
+                    request_message_id
+                );
+                return Ok(());
+            }
+            // May still be being approved, the next check deletes it
+            Some(request)
+                if request
+                    .decided_at
+                    .is_some_and(|decided_at| decided_at.timestamp_millis() > recent_cutoff) =>
+            {
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+    }
+
+    if let Some(request) = request {
+        let mut existing_request = log_channel_id
+            .message(&ctx.http, request.log_message_id.parse::<u64>()?)
+            .await
+            .context("Could not get request log message")?;
+
+        let embed = existing_request
+            .embeds
+            .first()
+            .context("Could not get first embed")?
+            .clone();
+
+        edit_request(
+            ctx,
+            &mut existing_request,
+            "Request Expired",
+            None,
+            None,
+            false,
+        )
+        .await
+        .context("Could not edit request message")?;
+
+        let result = delete_user_request(ctx, &embed).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
+    }
+
+    delete_staged_image(ctx, request_message_id).await
+}
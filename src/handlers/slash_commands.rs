@@ -0,0 +1,391 @@
+use anyhow::{bail, Context as AnyhowContext};
+use serenity::{
+    all::{
+        CommandInteraction, CommandOptionType, CommandType, MessageId, Permissions, ResolvedOption,
+        ResolvedTarget, ResolvedValue, UserId,
+    },
+    builder::{
+        CreateCommand, CreateCommandOption, CreateEmbed, CreateMessage, EditInteractionResponse,
+    },
+    client::Context,
+    model::channel::Message,
+};
+
+use crate::{
+    auth::HasAuth,
+    handlers::{commands::ban_reply, info::user_info_embed},
+    moderation::{
+        approve_request, ban_user, deny_request, parse_duration, remove_background,
+        request_log_message, set_background, unban_user, Removal,
+    },
+    responses::{edit_deferred_command_reply, get_request_message_id},
+    structs::{AuditAction, BackgroundRequest, Config, Repositories, RequestStatus},
+};
+
+/// Registers the slash and context menu commands in the configured guild, replacing any previous set.
+/// Moderator commands are hidden from members without the default permission, but every
+/// invocation is still checked against the auth role.
+pub async fn register_slash_commands(ctx: &Context) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let guild_id = data
+        .get::<Config>()
+        .context("Could not get config")?
+        .server
+        .guild_id;
+    drop(data);
+
+    let user_option = |description: &str| {
+        CreateCommandOption::new(CommandOptionType::User, "user", description).required(true)
+    };
+
+    let commands = vec![
+        CreateCommand::new("remove")
+            .description("Remove a user's background")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .add_option(user_option("User whose background to remove")),
+        CreateCommand::new("ban")
+            .description("Ban a user from submitting backgrounds")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .add_option(user_option("User to ban"))
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "duration",
+                "How long to ban for, e.g. 30m, 12h, 7d or 2w. Permanent when left out",
+            ))
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::String,
+                "reason",
+                "Why the user is banned",
+            )),
+        CreateCommand::new("unban")
+            .description("Allow a banned user to submit backgrounds again")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .add_option(user_option("User to unban")),
+        CreateCommand::new("remove-mine").description("Remove your own background"),
+        CreateCommand::new("usrbg")
+            .description("Look up and manage backgrounds")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "info",
+                    "Show a user's background, ban and requests",
+                )
+                .add_sub_option(user_option("User to look up")),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "set",
+                    "Set or replace a user's background without a request",
+                )
+                .add_sub_option(user_option("User whose background to set"))
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Attachment,
+                    "image",
+                    "Image to use as the background",
+                ))
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "url",
+                    "Link to the image, when not attaching one",
+                ))
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "notify",
+                    "Send the user a direct message about the change",
+                )),
+            ),
+        // Context menu entries on users and on messages in the request or log channel
+        CreateCommand::new("Ban requester")
+            .kind(CommandType::User)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+        CreateCommand::new("Remove background")
+            .kind(CommandType::User)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+        CreateCommand::new("Approve request")
+            .kind(CommandType::Message)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+        CreateCommand::new("Deny request")
+            .kind(CommandType::Message)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+    ];
+
+    guild_id
+        .set_commands(&ctx.http, commands)
+        .await
+        .context("Could not register slash commands")?;
+    Ok(())
+}
+
+pub async fn handle_slash_command(ctx: Context, command: CommandInteraction) -> anyhow::Result<()> {
+    command
+        .defer_ephemeral(&ctx.http)
+        .await
+        .context("Could not defer command response")?;
+
+    let result = match command.data.name.as_str() {
+        "remove-mine" => remove_background(
+            &ctx,
+            &command.user.id.to_string(),
+            command.user.id,
+            AuditAction::SelfRemove,
+        )
+        .await
+        .map(|removal| removal_reply(&removal, "you do not have a usrbg")),
+        name => {
+            let has_auth = command
+                .member
+                .as_ref()
+                .context("Could not get member from command")?
+                .has_auth(&ctx)
+                .await?;
+            if !has_auth {
+                Ok(text_reply("You are not allowed to use this command"))
+            } else if command.data.kind == CommandType::ChatInput {
+                handle_moderator_command(&ctx, &command, name).await
+            } else {
+                handle_context_menu_command(&ctx, &command, name).await
+            }
+        }
+    };
+
+    match result {
+        Ok(reply) => edit_deferred_command_reply(&ctx, &command, reply).await,
+        Err(err) => {
+            let reply = text_reply(&format!("Command failed: {:#}", err));
+            edit_deferred_command_reply(&ctx, &command, reply).await?;
+            Err(err)
+        }
+    }
+}
+
+async fn handle_moderator_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+    name: &str,
+) -> anyhow::Result<EditInteractionResponse> {
+    // `/usrbg <subcommand>` is handled like a command named `usrbg <subcommand>`
+    let options = command.data.options();
+    let (name, options) = match options.as_slice() {
+        [ResolvedOption {
+            name: subcommand,
+            value: ResolvedValue::SubCommand(subcommand_options),
+            ..
+        }] => (
+            format!("{} {}", name, subcommand),
+            subcommand_options.clone(),
+        ),
+        _ => (name.to_owned(), options.clone()),
+    };
+    let user_id = user_option(&options).context("Missing user option")?;
+    let uid = user_id.to_string();
+    let moderator = command.user.id;
+
+    match name.as_str() {
+        "remove" => {
+            let removal = remove_background(ctx, &uid, moderator, AuditAction::AdminRemove).await?;
+            Ok(removal_reply(&removal, "user does not have a usrbg"))
+        }
+        "ban" => {
+            let duration = match string_option(&options, "duration") {
+                Some(duration) => match parse_duration(duration) {
+                    Some(duration) => Some(duration),
+                    None => {
+                        return Ok(text_reply(&format!(
+                            "Invalid duration `{}`, expected e.g. 30m, 12h, 7d or 2w",
+                            duration
+                        )))
+                    }
+                },
+                None => None,
+            };
+            let reason = string_option(&options, "reason").map(str::to_owned);
+
+            let entry = ban_user(ctx, &uid, moderator, duration, reason).await?;
+            Ok(text_reply(&ban_reply(&entry)))
+        }
+        "unban" => {
+            if unban_user(ctx, &uid, moderator).await? {
+                Ok(text_reply("unbanned user"))
+            } else {
+                Ok(text_reply("user is not banned"))
+            }
+        }
+        "usrbg info" => {
+            let embed = user_info_embed(ctx, &uid).await?;
+            Ok(EditInteractionResponse::new().embed(embed))
+        }
+        "usrbg set" => {
+            let attachment_url = options.iter().find_map(|option| match option.value {
+                ResolvedValue::Attachment(attachment) if option.name == "image" => {
+                    Some(attachment.url.as_str())
+                }
+                _ => None,
+            });
+            let image_url = match (attachment_url, string_option(&options, "url")) {
+                (Some(image_url), None) | (None, Some(image_url)) => image_url,
+                _ => return Ok(text_reply("Give either an image or a url")),
+            };
+            let notify = options.iter().any(|option| {
+                option.name == "notify" && matches!(option.value, ResolvedValue::Boolean(true))
+            });
+
+            let img = set_background(ctx, &uid, image_url, moderator).await?;
+
+            let mut reply = format!("usrbg set to <{}>", img);
+            if notify {
+                let message = CreateMessage::new()
+                    .content("A moderator has set your usrbg")
+                    .embed(CreateEmbed::new().image(&img));
+                let result = match user_id.create_dm_channel(&ctx.http).await {
+                    Ok(channel) => channel.send_message(&ctx.http, message).await,
+                    Err(err) => Err(err),
+                };
+                if result.is_err() {
+                    println!("{:?}", result);
+                    reply += ", but the user could not be notified";
+                }
+            }
+            Ok(text_reply(&reply))
+        }
+        _ => bail!("Unknown command {}", name),
+    }
+}
+
+async fn handle_context_menu_command(
+    ctx: &Context,
+    command: &CommandInteraction,
+    name: &str,
+) -> anyhow::Result<EditInteractionResponse> {
+    let moderator = command.user.id;
+
+    match command.data.target() {
+        Some(ResolvedTarget::User(user, _)) => {
+            let uid = user.id.to_string();
+            match name {
+                "Ban requester" => {
+                    let entry = ban_user(ctx, &uid, moderator, None, None).await?;
+                    Ok(text_reply(&ban_reply(&entry)))
+                }
+                "Remove background" => {
+                    let removal =
+                        remove_background(ctx, &uid, moderator, AuditAction::AdminRemove).await?;
+                    Ok(removal_reply(&removal, "user does not have a usrbg"))
+                }
+                _ => bail!("Unknown user command {}", name),
+            }
+        }
+        Some(ResolvedTarget::Message(message)) => {
+            let request = match targeted_request(ctx, message).await? {
+                Some(request) => request,
+                None => {
+                    return Ok(text_reply(
+                        "This is not a background request or its log message",
+                    ))
+                }
+            };
+            if request.status != RequestStatus::Pending {
+                return Ok(text_reply("Request has already been handled"));
+            }
+
+            let mut log_message = request_log_message(ctx, &request).await?;
+            let request_message_id = MessageId::new(request.request_message_id.parse()?);
+
+            match name {
+                "Approve request" => {
+                    let img = approve_request(
+                        ctx,
+                        &mut log_message,
+                        &request.uid,
+                        &request.image_url,
+                        request_message_id,
+                        moderator,
+                    )
+                    .await?;
+                    match img {
+                        Some(_) => Ok(text_reply("Request approved")),
+                        None => Ok(text_reply("Request has already been handled")),
+                    }
+                }
+                "Deny request" => {
+                    let denied = deny_request(
+                        ctx,
+                        &mut log_message,
+                        &request.uid,
+                        request_message_id,
+                        moderator,
+                    )
+                    .await?;
+                    if denied {
+                        Ok(text_reply("Request denied"))
+                    } else {
+                        Ok(text_reply("Request has already been handled"))
+                    }
+                }
+                _ => bail!("Unknown message command {}", name),
+            }
+        }
+        _ => bail!("Could not get target of {}", name),
+    }
+}
+
+/// Looks up the request a message belongs to, which is either the user's original
+/// message in the request channel or the moderators' copy in the log channel
+async fn targeted_request(
+    ctx: &Context,
+    message: &Message,
+) -> anyhow::Result<Option<BackgroundRequest>> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let request_channel_id = config.server.request_channel_id;
+    let log_channel_id = config.server.log_channel_id;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    let request_message_id = if message.channel_id == request_channel_id {
+        message.id
+    } else if message.channel_id == log_channel_id {
+        // Only request log embeds link to the original request
+        match message.embeds.first() {
+            Some(embed) if embed.url.is_some() => get_request_message_id(embed)?,
+            _ => return Ok(None),
+        }
+    } else {
+        return Ok(None);
+    };
+
+    repositories
+        .requests
+        .get(&request_message_id.to_string())
+        .await
+}
+
+fn text_reply(text: &str) -> EditInteractionResponse {
+    EditInteractionResponse::new().content(text)
+}
+
+fn removal_reply(removal: &Removal, not_found: &str) -> EditInteractionResponse {
+    if removal.found {
+        text_reply(&removal.summary())
+    } else {
+        text_reply(not_found)
+    }
+}
+
+fn user_option(options: &[ResolvedOption]) -> Option<UserId> {
+    options.iter().find_map(|option| match option.value {
+        ResolvedValue::User(user, _) if option.name == "user" => Some(user.id),
+        _ => None,
+    })
+}
+
+fn string_option<'a>(options: &[ResolvedOption<'a>], name: &str) -> Option<&'a str> {
+    options.iter().find_map(|option| match option.value {
+        ResolvedValue::String(value) if option.name == name => Some(value),
+        _ => None,
+    })
+}
@@ -1,26 +1,51 @@
+mod admin_api;
+mod api;
+mod audit;
 mod auth;
+mod backup;
+mod cdn;
 mod database;
+mod dataset;
 mod handlers;
-mod imgur;
+mod metrics;
+mod migrations;
+mod moderation;
+mod repositories;
 mod responses;
+mod retry;
 mod s3bucket;
+mod signing;
 mod structs;
+mod webhooks;
 
 use anyhow::Context as AnyhowContext;
-use database::connect_database;
+use api::{check_admin_tokens, serve_api};
+use auth::remove_expired_bans;
+use backup::{backup, restore};
+use dataset::{load_dataset, publish_dataset_changes};
 use handlers::{
-    commands::handle_commands, components::handle_component_interaction,
-    requests::handle_user_request,
+    commands::handle_commands,
+    components::handle_component_interaction,
+    requests::{expire_pending_requests, handle_user_request},
+    slash_commands::{handle_slash_command, register_slash_commands},
 };
+use migrations::run_migrations;
+use moderation::{store_cancellation, Claim};
+use repositories::{connect_repositories, copy_repositories};
 use responses::edit_request;
-use s3bucket::connect_bucket;
-use structs::{Collections, Config, PendingRequestMidStore, PendingRequestUidStore, S3Bucket};
-
-use std::{collections::HashMap, fs};
+use s3bucket::{connect_bucket, delete_staged_image};
+use signing::{configured_fingerprint, verify_dataset};
+use structs::{Config, DatasetPublisher, Repositories, S3Bucket, UsrbgCache};
+
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use reqwest::Client;
 use serenity::{
-    all::{MessageId, UserId},
+    all::MessageId,
     async_trait,
     client::{Context, EventHandler},
     model::{
@@ -35,6 +60,9 @@ use crate::{responses::send_ephemeral_interaction_followup_reply, structs::HttpC
 
 struct Handler;
 
+// Background tasks are started on the first ready event only, ready fires again on reconnects
+static BACKGROUND_TASKS_STARTED: AtomicBool = AtomicBool::new(false);
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
@@ -46,7 +74,9 @@ impl EventHandler for Handler {
         if msg.channel_id == config.server.request_channel_id {
             drop(data);
             tokio::spawn(handle_user_request(ctx, msg));
-        } else if msg.channel_id == config.server.command_channel_id {
+        } else if config.settings.text_commands
+            && msg.channel_id == config.server.command_channel_id
+        {
             drop(data);
             tokio::spawn(handle_commands(ctx, msg));
         }
@@ -60,109 +90,89 @@ impl EventHandler for Handler {
         _guild_id: Option<GuildId>,
     ) {
         tokio::spawn(async move {
-            let mut data = ctx.data.write().await;
-
-            let pending_request_mid_store = data
-                .get_mut::<PendingRequestMidStore>()
-                .context("Could not get pending request store")
-                .expect("Could not get pending request mid store");
-
-            let message_id = pending_request_mid_store.remove(&deleted_message_id);
-
-            let config = data
+            let data = ctx.data.read().await;
+            let repositories = data
+                .get::<Repositories>()
+                .expect("Could not get repositories from data")
+                .clone();
+            let log_channel_id = data
                 .get::<Config>()
-                .expect("Could not get config from data");
-
-            match message_id {
-                Some(message_id) => {
-                    let existing_request = config
-                        .server
-                        .log_channel_id
-                        .message(&ctx.http, message_id)
-                        .await;
+                .expect("Could not get config from data")
+                .server
+                .log_channel_id;
+            drop(data);
 
-                    match existing_request {
-                        Ok(mut existing_request) => {
-                            let result = edit_request(
-                                &ctx,
-                                &mut existing_request,
-                                "Request Cancelled",
-                                None,
-                                None,
-                                false,
-                            )
-                            .await
-                            .context("Could not edit request message");
-                            if result.is_err() {
-                                println!("{:?}", result);
-                            }
-                        }
-                        Err(_) => {}
-                    }
+            // Every deleted message ends up here, only ones with a stored request are requests
+            let request = match repositories
+                .requests
+                .get(&deleted_message_id.to_string())
+                .await
+            {
+                Ok(Some(request)) => request,
+                Ok(None) => return,
+                Err(err) => {
+                    println!("{:?}", err);
+                    return;
+                }
+            };
+
+            // Only the requester can delete their request message
+            let claim = store_cancellation(&repositories, &request.uid, deleted_message_id).await;
+            match claim {
+                Ok(Claim::Claimed(_)) => {}
+                Ok(Claim::Untracked | Claim::AlreadyHandled) => return,
+                Err(err) => {
+                    println!("{:?}", err);
+                    return;
                 }
-                None => {}
+            }
+
+            let existing_request = match request.log_message_id.parse::<u64>() {
+                Ok(message_id) => log_channel_id.message(&ctx.http, message_id).await,
+                Err(err) => {
+                    println!("{:?}", err);
+                    return;
+                }
+            };
+
+            if let Ok(mut existing_request) = existing_request {
+                let result = edit_request(
+                    &ctx,
+                    &mut existing_request,
+                    "Request Cancelled",
+                    None,
+                    None,
+                    false,
+                )
+                .await
+                .context("Could not edit request message");
+                if result.is_err() {
+                    println!("{:?}", result);
+                }
+            }
+
+            let result = delete_staged_image(&ctx, deleted_message_id).await;
+            if result.is_err() {
+                println!("{:?}", result);
             }
         });
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
         match interaction {
-            Interaction::Component(mut component_interaction) => {
+            Interaction::Component(component_interaction) => {
                 tokio::spawn(async move {
                     let result =
                         handle_component_interaction(ctx.clone(), component_interaction.clone())
                             .await;
-                    if result.is_err() {
-                        println!("{:?}", result);
-
-                        let embed = component_interaction.message.embeds.first();
-
-                        match embed {
-                            Some(embed) => {
-                                let embed = embed.clone();
-
-                                let thumbnail;
-
-                                match &embed.thumbnail {
-                                    Some(embed_thumbnail) => {
-                                        thumbnail = Some(embed_thumbnail.url.as_str());
-                                    }
-                                    None => {
-                                        thumbnail = None;
-                                    }
-                                }
-
-                                let url;
-
-                                match &embed.url {
-                                    Some(embed_url) => {
-                                        url = Some(embed_url.as_str());
-                                    }
-                                    None => {
-                                        url = None;
-                                    }
-                                }
-
-                                let result = edit_request(
-                                    &ctx,
-                                    &mut component_interaction.message,
-                                    "Request Pending",
-                                    thumbnail,
-                                    url,
-                                    true,
-                                )
-                                .await;
-                                if result.is_err() {
-                                    println!("{:?}", result);
-                                }
-                            }
-                            None => {}
-                        }
+                    if let Err(err) = result {
+                        println!("{:?}", err);
 
+                        // A failed approval has already restored the request log message
                         let result = send_ephemeral_interaction_followup_reply(
                             &ctx,
                             component_interaction,
-                            "Failed to accept request",
+                            &format!("Failed to accept request: {:#}", err),
                         )
                         .await;
                         match result {
@@ -174,47 +184,141 @@ impl EventHandler for Handler {
                     }
                 });
             }
+            Interaction::Command(command) => {
+                tokio::spawn(async move {
+                    let result = handle_slash_command(ctx, command).await;
+                    if result.is_err() {
+                        println!("{:?}", result);
+                    }
+                });
+            }
             _ => {}
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn ready(&self, ctx: Context, ready: Ready) {
         println!("{} is connected!", ready.user.name);
+
+        let result = register_slash_commands(&ctx).await;
+        if result.is_err() {
+            println!("{:?}", result);
+        }
+
+        if !BACKGROUND_TASKS_STARTED.swap(true, Ordering::SeqCst) {
+            tokio::spawn(expire_pending_requests(ctx.clone()));
+            tokio::spawn(remove_expired_bans(ctx.clone()));
+            tokio::spawn(serve_api(ctx.clone()));
+            tokio::spawn(publish_dataset_changes(ctx));
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let config_file_location;
-
-    match std::env::consts::OS {
-        "linux" => {
-            config_file_location = "/etc/blackcube-rs/blackcube-rs.toml";
-        }
-        "windows" => {
-            config_file_location = "C:\\ProgramData\\blackcube-rs\\blackcube-rs.toml";
-        }
-        _ => {
-            unreachable!();
+    let config_file_location = match std::env::consts::OS {
+        "linux" => "/etc/blackcube-rs/blackcube-rs.toml",
+        "windows" => "C:\\ProgramData\\blackcube-rs\\blackcube-rs.toml",
+        _ => unreachable!(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    let subcommand = args.next();
+
+    // Verification works without a config when a fingerprint is given, e.g. on a client
+    if subcommand.as_deref() == Some("verify") {
+        let usage = "Usage: blackcube-rs verify <dataset> <signature> [fingerprint]";
+        let dataset_path = args.next().expect(usage);
+        let signature_path = args.next().expect(usage);
+        let expected_fingerprint = match args.next() {
+            Some(expected_fingerprint) => Ok(expected_fingerprint),
+            None => configured_fingerprint(&read_config(config_file_location)),
+        };
+        let result = expected_fingerprint.and_then(|expected_fingerprint| {
+            verify_dataset(&dataset_path, &signature_path, &expected_fingerprint)
+        });
+        match result {
+            Ok(()) => println!("Signature is valid"),
+            Err(err) => {
+                println!("{:#}", err);
+                std::process::exit(1);
+            }
         }
+        return;
     }
 
-    let config: Config = toml::from_str(
-        &fs::read_to_string(config_file_location)
-            .expect("Could not read configuration file, make sure the config is located at /etc/blackcube-rs/blackcube-rs.toml or C:\\ProgramData\\blackcube-rs\\blackcube-rs.toml")
-    ).expect("could not read config");
+    let config = read_config(config_file_location);
+
+    if let Some(http_config) = &config.http {
+        check_admin_tokens(http_config).expect("Invalid http config");
+    }
 
     let bucket = connect_bucket(&config)
         .await
         .expect("Could not initialize storage bucket connection");
 
-    let collections: Collections =
-        connect_database(&config).expect("Could not connect to database");
+    let repositories: Repositories = connect_repositories(&config)
+        .await
+        .expect("Could not connect to database");
+
+    // Maintenance commands run before indexes and migrations, so a backup is taken of the
+    // data as it was and a restore or migration starts from an untouched database
+    match subcommand.as_deref() {
+        Some("backup") => {
+            let archive_path = args.next().expect("Usage: blackcube-rs backup <archive>");
+            backup(&config, &bucket, &repositories, &archive_path)
+                .await
+                .expect("Backup failed");
+            return;
+        }
+        Some("migrate-from-mongo") => {
+            let usage = "Usage: blackcube-rs migrate-from-mongo <mongodb url> <database name>";
+            let mongo_url = args.next().expect(usage);
+            let mongo_name = args.next().expect(usage);
+            let source = repositories::mongo::connect_to(&config, &mongo_url, &mongo_name)
+                .await
+                .expect("Could not connect to MongoDB");
+            copy_repositories(&source, &repositories)
+                .await
+                .expect("Migration from MongoDB failed");
+            return;
+        }
+        Some("restore") => {
+            let archive_path = args.next().expect("Usage: blackcube-rs restore <archive>");
+            restore(&config, &bucket, &repositories, &archive_path)
+                .await
+                .expect("Restore failed");
+            return;
+        }
+        _ => {}
+    }
+
+    let duplicates = repositories
+        .schema
+        .ensure_indexes()
+        .await
+        .expect("Could not create database indexes");
+    for duplicate in duplicates {
+        println!(
+            "{} contains duplicate entries for uids {:?}, unique index not created. Run ~dedupe to keep only the newest entry for each",
+            duplicate.collection, duplicate.uids
+        );
+    }
+
+    let http_client: Client = Client::builder()
+        .connect_timeout(Duration::from_secs(
+            config.settings.download_connect_timeout_secs,
+        ))
+        .build()
+        .expect("Could not build http client");
 
-    let http_client: Client = Client::new();
+    run_migrations(&config, &repositories, &http_client)
+        .await
+        .expect("Could not migrate database");
 
-    let pending_request_uid_store: HashMap<UserId, MessageId> = HashMap::new();
-    let pending_request_mid_store: HashMap<MessageId, MessageId> = HashMap::new();
+    let usrbg_cache = UsrbgCache::default();
+    load_dataset(&repositories, &usrbg_cache)
+        .await
+        .expect("Could not load usrbg dataset");
 
     let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
     let mut client = serenity::Client::builder(&config.bot.discord_token, intents)
@@ -226,14 +330,21 @@ async fn main() {
     let mut data = client.data.write().await;
     data.insert::<Config>(config);
     data.insert::<S3Bucket>(bucket);
-    data.insert::<Collections>(collections);
+    data.insert::<Repositories>(repositories);
+    data.insert::<UsrbgCache>(usrbg_cache);
+    data.insert::<DatasetPublisher>(DatasetPublisher::default());
     data.insert::<HttpClient>(HttpClient {
         client: http_client,
     });
-    data.insert::<PendingRequestUidStore>(pending_request_uid_store);
-    data.insert::<PendingRequestMidStore>(pending_request_mid_store);
 
     drop(data);
 
     client.start().await.expect("Error starting client");
 }
+
+fn read_config(config_file_location: &str) -> Config {
+    toml::from_str(
+        &fs::read_to_string(config_file_location)
+            .expect("Could not read configuration file, make sure the config is located at /etc/blackcube-rs/blackcube-rs.toml or C:\\ProgramData\\blackcube-rs\\blackcube-rs.toml")
+    ).expect("could not read config")
+}
@@ -0,0 +1,161 @@
+use std::sync::LazyLock;
+
+use anyhow::Context as AnyhowContext;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::structs::{BackgroundRequest, Repositories, RequestStatus, UsrbgCache};
+
+// Moderators handle requests within minutes to days
+const DECISION_LATENCY_BUCKETS: [f64; 10] = [
+    60.0, 300.0, 900.0, 3600.0, 10800.0, 21600.0, 43200.0, 86400.0, 259200.0, 604800.0,
+];
+
+pub static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new().expect("Could not register metrics"));
+
+pub struct Metrics {
+    registry: Registry,
+    requests_submitted: IntCounter,
+    requests_rejected: IntCounterVec,
+    requests_resolved: IntCounterVec,
+    decision_latency: HistogramVec,
+    s3_upload_duration: Histogram,
+    pending_requests: IntGauge,
+    backgrounds: IntGauge,
+}
+
+/// Why a request was deleted without being logged for moderators
+#[derive(Debug, Clone, Copy)]
+pub enum RejectionReason {
+    Blacklisted,
+    NoAttachment,
+    TooLarge,
+    BadType,
+    /// The image could not be copied into the bucket, e.g. it was only found to be too
+    /// large while downloading
+    StagingFailed,
+}
+
+impl RejectionReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::Blacklisted => "blacklisted",
+            RejectionReason::NoAttachment => "no_attachment",
+            RejectionReason::TooLarge => "too_large",
+            RejectionReason::BadType => "bad_type",
+            RejectionReason::StagingFailed => "staging_failed",
+        }
+    }
+}
+
+impl Metrics {
+    fn new() -> anyhow::Result<Metrics> {
+        let registry = Registry::new_custom(Some("blackcube".to_owned()), None)?;
+
+        let requests_submitted =
+            IntCounter::new("requests_submitted_total", "Background requests submitted")?;
+        let requests_rejected = IntCounterVec::new(
+            Opts::new(
+                "requests_rejected_total",
+                "Background requests deleted before reaching moderators",
+            ),
+            &["reason"],
+        )?;
+        let requests_resolved = IntCounterVec::new(
+            Opts::new(
+                "requests_resolved_total",
+                "Background requests approved, denied, cancelled or expired",
+            ),
+            &["status"],
+        )?;
+        let decision_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "request_decision_latency_seconds",
+                "Time from submitting a request to a moderator approving or denying it",
+            )
+            .buckets(DECISION_LATENCY_BUCKETS.to_vec()),
+            &["status"],
+        )?;
+        let s3_upload_duration = Histogram::with_opts(HistogramOpts::new(
+            "s3_upload_duration_seconds",
+            "Time taken to upload an image to the bucket",
+        ))?;
+        let pending_requests = IntGauge::new("pending_requests", "Requests awaiting a decision")?;
+        let backgrounds = IntGauge::new("backgrounds", "Users with a background")?;
+
+        registry.register(Box::new(requests_submitted.clone()))?;
+        registry.register(Box::new(requests_rejected.clone()))?;
+        registry.register(Box::new(requests_resolved.clone()))?;
+        registry.register(Box::new(decision_latency.clone()))?;
+        registry.register(Box::new(s3_upload_duration.clone()))?;
+        registry.register(Box::new(pending_requests.clone()))?;
+        registry.register(Box::new(backgrounds.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests_submitted,
+            requests_rejected,
+            requests_resolved,
+            decision_latency,
+            s3_upload_duration,
+            pending_requests,
+            backgrounds,
+        })
+    }
+
+    pub fn request_submitted(&self) {
+        self.requests_submitted.inc();
+    }
+
+    pub fn request_rejected(&self, reason: RejectionReason) {
+        self.requests_rejected
+            .with_label_values(&[reason.as_str()])
+            .inc();
+    }
+
+    pub fn request_resolved(&self, request: &BackgroundRequest) {
+        let status = match request.status {
+            RequestStatus::Pending => return,
+            RequestStatus::Approved => "approved",
+            RequestStatus::Denied => "denied",
+            RequestStatus::Cancelled => "cancelled",
+            RequestStatus::Expired => "expired",
+        };
+        self.requests_resolved.with_label_values(&[status]).inc();
+
+        if let (RequestStatus::Approved | RequestStatus::Denied, Some(decided_at)) =
+            (request.status, request.decided_at)
+        {
+            let latency_millis =
+                decided_at.timestamp_millis() - request.created_at.timestamp_millis();
+            self.decision_latency
+                .with_label_values(&[status])
+                .observe(latency_millis.max(0) as f64 / 1000.0);
+        }
+    }
+
+    /// Observes the upload duration when the returned timer is dropped
+    pub fn s3_upload_timer(&self) -> HistogramTimer {
+        self.s3_upload_duration.start_timer()
+    }
+
+    /// Renders every metric in the Prometheus text format. Gauges are read from the
+    /// database and cache at scrape time so they cannot drift.
+    pub async fn render(
+        &self,
+        repositories: &Repositories,
+        cache: &UsrbgCache,
+    ) -> anyhow::Result<String> {
+        let pending = repositories.requests.count_pending().await?;
+        self.pending_requests.set(pending as i64);
+        self.backgrounds
+            .set(cache.dataset.read().await.entries.len() as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).context("Metrics are not valid utf-8")
+    }
+}
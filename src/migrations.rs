@@ -0,0 +1,97 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Client,
+};
+
+use crate::{
+    s3bucket::referenced_object_key,
+    structs::{Config, Repositories},
+};
+
+pub const USRBG_SCHEMA: &str = "usrbg";
+
+/// Schema version of usrbg entries written by this build
+///
+/// 1: provenance and image metadata (approved_by, storage_key, byte_size, ...)
+pub const USRBG_SCHEMA_VERSION: u32 = 1;
+
+/// Migrates the usrbg collection one version at a time up to `USRBG_SCHEMA_VERSION`,
+/// recording the version after every step so an interrupted run resumes where it stopped.
+pub async fn run_migrations(
+    config: &Config,
+    repositories: &Repositories,
+    http_client: &Client,
+) -> anyhow::Result<()> {
+    let mut version = repositories.schema.schema_version(USRBG_SCHEMA).await?;
+
+    while version < USRBG_SCHEMA_VERSION {
+        match version {
+            0 => backfill_image_metadata(config, repositories, http_client).await?,
+            _ => unreachable!(),
+        }
+
+        version += 1;
+        repositories
+            .schema
+            .set_schema_version(USRBG_SCHEMA, version)
+            .await?;
+        println!("Migrated usrbg collection to schema version {}", version);
+    }
+
+    Ok(())
+}
+
+/// Fills in the storage key, byte size and mime type of existing backgrounds from a HEAD
+/// request to their image. Entries whose image cannot be reached are left as they are.
+async fn backfill_image_metadata(
+    config: &Config,
+    repositories: &Repositories,
+    http_client: &Client,
+) -> anyhow::Result<()> {
+    let entries = repositories.backgrounds.all().await?;
+    // Startup waits for the backfill, so an unresponsive image host must not stall it
+    let request_timeout = Duration::from_secs(config.settings.download_read_timeout_secs);
+
+    for mut entry in entries {
+        if entry.image.byte_size.is_some() {
+            continue;
+        }
+
+        let response = http_client
+            .head(&entry.img)
+            .timeout(request_timeout)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match response {
+            Ok(response) => {
+                let headers = response.headers();
+                entry.image.byte_size = headers
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok());
+                entry.image.mime_type = headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_owned());
+            }
+            Err(err) => {
+                println!("Could not get image metadata for {}: {}", entry.uid, err);
+            }
+        }
+
+        entry.storage_key = referenced_object_key(config, &entry);
+
+        repositories
+            .backgrounds
+            .upsert(entry)
+            .await
+            .context("Could not update usrbg entry")?;
+    }
+
+    Ok(())
+}
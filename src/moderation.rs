@@ -0,0 +1,1605 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context as AnyhowContext};
+use serde::Serialize;
+use serenity::{
+    all::{Embed, MessageId, UserId},
+    async_trait,
+    client::Context,
+    model::channel::Message,
+};
+
+use crate::{
+    audit::append_audit_entry,
+    cdn::purge_cdn,
+    dataset::{refresh_and_publish_dataset, usrbg_changed},
+    metrics::METRICS,
+    responses::{create_background_set_log_message, delete_user_request, edit_request},
+    s3bucket::{
+        delete_image_from_s3_bucket, delete_staged_image, object_key, promote_staged_image,
+        referenced_object_key, upload_image_to_s3bucket,
+    },
+    structs::{
+        AuditAction, AuditEntry, BackgroundRequest, Blacklist, Config, ImageMetadata, Repositories,
+        RequestStatus, Usrbg, WebhookEvent,
+    },
+    webhooks::{emit_webhook_event, WebhookEventData},
+};
+
+// Moderation actions shared by the Discord handlers and the admin API. Each one updates the
+// database, the request log message, the audit log and the webhooks.
+
+/// What moderating does outside the database: the bucket, the cached dataset and webhooks.
+/// Implemented by the Discord context, tests stub it.
+#[async_trait]
+pub trait ModerationEffects: Send + Sync {
+    /// Bucket key a user's background is stored at
+    async fn storage_key(&self, uid: &str) -> anyhow::Result<String>;
+    async fn promote_staged_image(
+        &self,
+        request_message_id: MessageId,
+        uid: &str,
+    ) -> anyhow::Result<Option<(String, ImageMetadata)>>;
+    async fn upload_image(
+        &self,
+        image_url: &str,
+        uid: &str,
+    ) -> anyhow::Result<(String, ImageMetadata)>;
+    async fn delete_staged_image(&self, request_message_id: MessageId) -> anyhow::Result<()>;
+    async fn usrbg_changed(&self);
+    async fn emit_webhook_event(&self, event: WebhookEvent, data: WebhookEventData);
+}
+
+#[async_trait]
+impl ModerationEffects for Context {
+    async fn storage_key(&self, uid: &str) -> anyhow::Result<String> {
+        let data = self.data.read().await;
+        let config = data.get::<Config>().context("Could not get config")?;
+        Ok(object_key(config, uid))
+    }
+
+    async fn promote_staged_image(
+        &self,
+        request_message_id: MessageId,
+        uid: &str,
+    ) -> anyhow::Result<Option<(String, ImageMetadata)>> {
+        promote_staged_image(self, request_message_id, uid.to_owned()).await
+    }
+
+    async fn upload_image(
+        &self,
+        image_url: &str,
+        uid: &str,
+    ) -> anyhow::Result<(String, ImageMetadata)> {
+        upload_image_to_s3bucket(self, image_url.to_owned(), uid.to_owned()).await
+    }
+
+    async fn delete_staged_image(&self, request_message_id: MessageId) -> anyhow::Result<()> {
+        delete_staged_image(self, request_message_id).await
+    }
+
+    async fn usrbg_changed(&self) {
+        usrbg_changed(self).await
+    }
+
+    async fn emit_webhook_event(&self, event: WebhookEvent, data: WebhookEventData) {
+        emit_webhook_event(self, event, data).await
+    }
+}
+
+/// The moderators' log message of a request, and the user's request message it links to
+#[async_trait]
+pub trait RequestLog: Send {
+    async fn show_uploading(&mut self, image_url: &str) -> anyhow::Result<()>;
+    /// Puts the buttons back so the request can be acted on again
+    async fn show_pending(&mut self) -> anyhow::Result<()>;
+    async fn show_approved(&mut self, image_url: &str) -> anyhow::Result<()>;
+    async fn show_denied(&mut self) -> anyhow::Result<()>;
+    async fn delete_user_request(&self) -> anyhow::Result<()>;
+}
+
+pub struct DiscordRequestLog<'a> {
+    ctx: &'a Context,
+    log_message: &'a mut Message,
+    /// The embed as it was logged, before any edits
+    embed: Embed,
+}
+
+impl<'a> DiscordRequestLog<'a> {
+    pub fn new(ctx: &'a Context, log_message: &'a mut Message) -> anyhow::Result<Self> {
+        let embed = log_message
+            .embeds
+            .first()
+            .context("Could not get first embed")?
+            .clone();
+        Ok(DiscordRequestLog {
+            ctx,
+            log_message,
+            embed,
+        })
+    }
+}
+
+#[async_trait]
+impl RequestLog for DiscordRequestLog<'_> {
+    async fn show_uploading(&mut self, image_url: &str) -> anyhow::Result<()> {
+        edit_request(
+            self.ctx,
+            self.log_message,
+            "Uploading...",
+            Some(image_url),
+            self.embed.url.as_deref(),
+            false,
+        )
+        .await
+    }
+
+    async fn show_pending(&mut self) -> anyhow::Result<()> {
+        let thumbnail = self
+            .embed
+            .thumbnail
+            .as_ref()
+            .map(|embed_thumbnail| embed_thumbnail.url.as_str());
+        edit_request(
+            self.ctx,
+            self.log_message,
+            "Request Pending",
+            thumbnail,
+            self.embed.url.as_deref(),
+            true,
+        )
+        .await
+    }
+
+    async fn show_approved(&mut self, image_url: &str) -> anyhow::Result<()> {
+        edit_request(
+            self.ctx,
+            self.log_message,
+            "Request Approved",
+            Some(image_url),
+            None,
+            false,
+        )
+        .await
+    }
+
+    async fn show_denied(&mut self) -> anyhow::Result<()> {
+        edit_request(
+            self.ctx,
+            self.log_message,
+            "Request Denied",
+            None,
+            None,
+            false,
+        )
+        .await
+    }
+
+    async fn delete_user_request(&self) -> anyhow::Result<()> {
+        delete_user_request(self.ctx, &self.embed).await
+    }
+}
+
+/// Approves a request logged in `log_message`, making its image the user's background.
+/// Returns the public URL of the new background, or `None` if the request was already
+/// handled.
+pub async fn approve_request(
+    ctx: &Context,
+    log_message: &mut Message,
+    uid: &str,
+    image_url: &str,
+    request_message_id: MessageId,
+    moderator: UserId,
+) -> anyhow::Result<Option<String>> {
+    let mut request_log = DiscordRequestLog::new(ctx, log_message)?;
+
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    approve_logged_request(
+        ctx,
+        &mut request_log,
+        &repositories,
+        uid,
+        image_url,
+        request_message_id,
+        moderator,
+    )
+    .await
+}
+
+/// Does the work of `approve_request`
+pub async fn approve_logged_request(
+    effects: &impl ModerationEffects,
+    request_log: &mut impl RequestLog,
+    repositories: &Repositories,
+    uid: &str,
+    image_url: &str,
+    request_message_id: MessageId,
+    moderator: UserId,
+) -> anyhow::Result<Option<String>> {
+    let storage_key = effects.storage_key(uid).await?;
+
+    // Claimed before uploading, so moderators approving at the same time cannot both upload
+    let request = match claim_request(
+        repositories,
+        request_message_id,
+        RequestStatus::Approved,
+        moderator,
+    )
+    .await?
+    {
+        Claim::Claimed(request) => Some(*request),
+        Claim::Untracked => None,
+        Claim::AlreadyHandled => return Ok(None),
+    };
+
+    let result: anyhow::Result<String> = async {
+        request_log
+            .show_uploading(image_url)
+            .await
+            .context("Could not update message to show loading state")?;
+
+        let promoted = effects
+            .promote_staged_image(request_message_id, uid)
+            .await
+            .context("Could not promote staged image")?;
+
+        // Requests made before images were staged still point at the Discord CDN
+        let (s3bucket_url, image) = match promoted {
+            Some(promoted) => promoted,
+            None => effects
+                .upload_image(image_url, uid)
+                .await
+                .context("Could not upload image to s3bucket")?,
+        };
+
+        let entry = Usrbg {
+            uid: uid.to_owned(),
+            img: s3bucket_url.clone(),
+            approved_by: Some(moderator.to_string()),
+            approved_at: Some(bson::DateTime::now()),
+            source_message_id: Some(request_message_id.to_string()),
+            storage_key: Some(storage_key),
+            image,
+        };
+        store_background(repositories, entry, AuditAction::Approve, moderator).await?;
+        Ok(s3bucket_url)
+    }
+    .await;
+
+    let s3bucket_url = match result {
+        Ok(s3bucket_url) => s3bucket_url,
+        Err(err) => {
+            // Lets the request be approved again once the problem is fixed. Without a record
+            // there is nothing to reopen, the request can be approved again as it is.
+            let reopened = match &request {
+                Some(_) => {
+                    let result = repositories
+                        .requests
+                        .reopen(&request_message_id.to_string())
+                        .await;
+                    if result.is_err() {
+                        println!("{:?}", result);
+                    }
+                    result.is_ok()
+                }
+                None => true,
+            };
+            if reopened {
+                let result = request_log.show_pending().await;
+                if result.is_err() {
+                    println!("{:?}", result);
+                }
+            }
+            return Err(err);
+        }
+    };
+    if let Some(request) = &request {
+        METRICS.request_resolved(request);
+    }
+    effects.usrbg_changed().await;
+
+    // Only deleted now, a failed approval is retried from the staged copy
+    let result = effects.delete_staged_image(request_message_id).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+
+    let mut event_data = WebhookEventData::new(uid);
+    event_data.actor = Some(moderator.to_string());
+    event_data.request_message_id = Some(request_message_id.to_string());
+    event_data.image_url = Some(s3bucket_url.clone());
+    effects
+        .emit_webhook_event(WebhookEvent::RequestApproved, event_data)
+        .await;
+
+    request_log
+        .show_approved(&s3bucket_url)
+        .await
+        .context("could not edit request message")?;
+
+    request_log
+        .delete_user_request()
+        .await
+        .context("Could not delete original request")?;
+
+    Ok(Some(s3bucket_url))
+}
+
+/// Denies a request logged in `log_message` and deletes its staged image. Returns `false`
+/// if the request was already handled.
+pub async fn deny_request(
+    ctx: &Context,
+    log_message: &mut Message,
+    uid: &str,
+    request_message_id: MessageId,
+    moderator: UserId,
+) -> anyhow::Result<bool> {
+    let mut request_log = DiscordRequestLog::new(ctx, log_message)?;
+
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    deny_logged_request(
+        ctx,
+        &mut request_log,
+        &repositories,
+        uid,
+        request_message_id,
+        moderator,
+    )
+    .await
+}
+
+/// Does the work of `deny_request`
+pub async fn deny_logged_request(
+    effects: &impl ModerationEffects,
+    request_log: &mut impl RequestLog,
+    repositories: &Repositories,
+    uid: &str,
+    request_message_id: MessageId,
+    moderator: UserId,
+) -> anyhow::Result<bool> {
+    let claim = store_denial(repositories, uid, request_message_id, moderator).await?;
+    if let Claim::AlreadyHandled = claim {
+        return Ok(false);
+    }
+
+    request_log
+        .show_denied()
+        .await
+        .context("Could not edit request message")?;
+
+    let mut event_data = WebhookEventData::new(uid);
+    event_data.actor = Some(moderator.to_string());
+    event_data.request_message_id = Some(request_message_id.to_string());
+    effects
+        .emit_webhook_event(WebhookEvent::RequestDenied, event_data)
+        .await;
+
+    let result = effects.delete_staged_image(request_message_id).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+
+    request_log
+        .delete_user_request()
+        .await
+        .context("Could not delete original request")?;
+
+    Ok(true)
+}
+
+/// Outcome of claiming a request, which resolves it before anything is done so only one
+/// moderator can act on it
+#[derive(Debug)]
+pub enum Claim {
+    Claimed(Box<BackgroundRequest>),
+    /// There is no record of the request, e.g. it was made before requests were stored
+    Untracked,
+    /// The request was already handled, e.g. by another moderator
+    AlreadyHandled,
+}
+
+pub async fn claim_request(
+    repositories: &Repositories,
+    request_message_id: MessageId,
+    status: RequestStatus,
+    moderator: UserId,
+) -> anyhow::Result<Claim> {
+    let request_message_id = request_message_id.to_string();
+
+    let request = repositories
+        .requests
+        .resolve(&request_message_id, status, Some(moderator.to_string()))
+        .await
+        .context("Could not claim request")?;
+    if let Some(request) = request {
+        return Ok(Claim::Claimed(Box::new(request)));
+    }
+
+    match repositories.requests.get(&request_message_id).await? {
+        Some(_) => Ok(Claim::AlreadyHandled),
+        None => Ok(Claim::Untracked),
+    }
+}
+
+/// Stores a new background for a user and audits the change as `action`
+pub async fn store_background(
+    repositories: &Repositories,
+    entry: Usrbg,
+    action: AuditAction,
+    moderator: UserId,
+) -> anyhow::Result<()> {
+    let existing = repositories.backgrounds.get(&entry.uid).await?;
+
+    let mut audit_entry = AuditEntry::new(action, moderator, &entry.uid);
+    audit_entry.before_img = existing.map(|existing| existing.img);
+    audit_entry.after_img = Some(entry.img.clone());
+
+    repositories
+        .backgrounds
+        .upsert(entry)
+        .await
+        .context("Could not upsert into database")?;
+    append_audit_entry(repositories, audit_entry).await;
+
+    Ok(())
+}
+
+/// Claims a request as denied and audits the denial
+pub async fn store_denial(
+    repositories: &Repositories,
+    uid: &str,
+    request_message_id: MessageId,
+    moderator: UserId,
+) -> anyhow::Result<Claim> {
+    let claim = claim_request(
+        repositories,
+        request_message_id,
+        RequestStatus::Denied,
+        moderator,
+    )
+    .await?;
+
+    match &claim {
+        Claim::Claimed(request) => METRICS.request_resolved(request),
+        Claim::Untracked => {}
+        Claim::AlreadyHandled => return Ok(claim),
+    }
+    let audit_entry = AuditEntry::new(AuditAction::Deny, moderator, uid);
+    append_audit_entry(repositories, audit_entry).await;
+
+    Ok(claim)
+}
+
+/// Marks a request as cancelled by the user who made it and audits the cancellation.
+/// Requests made before requests were stored can still be cancelled with the Cancel button
+/// of their log message, those are audited as well.
+pub async fn store_cancellation(
+    repositories: &Repositories,
+    uid: &str,
+    request_message_id: MessageId,
+) -> anyhow::Result<Claim> {
+    let request_message_id = request_message_id.to_string();
+
+    let request = repositories
+        .requests
+        .resolve(
+            &request_message_id,
+            RequestStatus::Cancelled,
+            Some(uid.to_owned()),
+        )
+        .await
+        .context("Could not cancel request")?;
+    let claim = match request {
+        Some(request) => {
+            METRICS.request_resolved(&request);
+            Claim::Claimed(Box::new(request))
+        }
+        None => match repositories.requests.get(&request_message_id).await? {
+            Some(_) => return Ok(Claim::AlreadyHandled),
+            None => Claim::Untracked,
+        },
+    };
+    let audit_entry = AuditEntry::new(AuditAction::Cancel, uid, uid);
+    append_audit_entry(repositories, audit_entry).await;
+
+    Ok(claim)
+}
+
+/// Fetches the message a request was logged in for moderators
+pub async fn request_log_message(
+    ctx: &Context,
+    request: &BackgroundRequest,
+) -> anyhow::Result<Message> {
+    let data = ctx.data.read().await;
+    let log_channel_id = data
+        .get::<Config>()
+        .context("Could not get config")?
+        .server
+        .log_channel_id;
+    drop(data);
+
+    log_channel_id
+        .message(&ctx.http, request.log_message_id.parse::<u64>()?)
+        .await
+        .context("Could not get request log message")
+}
+
+/// Sets a user's background to the image at `image_url` without a request. Returns the
+/// public URL of the new background.
+pub async fn set_background(
+    ctx: &Context,
+    uid: &str,
+    image_url: &str,
+    moderator: UserId,
+) -> anyhow::Result<String> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    let storage_key = object_key(data.get::<Config>().context("Could not get config")?, uid);
+    drop(data);
+
+    let (s3bucket_url, image) = upload_image_to_s3bucket(ctx, image_url.to_owned(), uid.to_owned())
+        .await
+        .context("Could not upload image to s3bucket")?;
+
+    let entry = Usrbg {
+        uid: uid.to_owned(),
+        img: s3bucket_url.clone(),
+        approved_by: Some(moderator.to_string()),
+        approved_at: Some(bson::DateTime::now()),
+        source_message_id: None,
+        storage_key: Some(storage_key),
+        image,
+    };
+    store_background(&repositories, entry, AuditAction::Set, moderator).await?;
+    usrbg_changed(ctx).await;
+
+    let mut event_data = WebhookEventData::new(uid);
+    event_data.actor = Some(moderator.to_string());
+    event_data.image_url = Some(s3bucket_url.clone());
+    emit_webhook_event(ctx, WebhookEvent::BackgroundSet, event_data).await;
+
+    let result = create_background_set_log_message(ctx, uid, moderator, &s3bucket_url).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+
+    Ok(s3bucket_url)
+}
+
+/// Outcome of one part of removing a background
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase", tag = "status", content = "error")]
+pub enum RemovalStep {
+    Done,
+    /// Started in the background, failures are reported to the log channel
+    Queued,
+    /// Nothing to do, e.g. the image of a legacy entry is hosted elsewhere or the CDN purge
+    /// is not configured
+    Skipped,
+    Failed(String),
+}
+
+impl RemovalStep {
+    fn from_result(result: anyhow::Result<()>) -> RemovalStep {
+        match result {
+            Ok(()) => RemovalStep::Done,
+            Err(err) => {
+                println!("{:?}", err);
+                RemovalStep::Failed(format!("{:#}", err))
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            RemovalStep::Done => "done".to_owned(),
+            RemovalStep::Queued => "queued".to_owned(),
+            RemovalStep::Skipped => "skipped".to_owned(),
+            RemovalStep::Failed(err) => format!("failed, {}", err),
+        }
+    }
+}
+
+/// Which parts of a removal succeeded. The database entry is always deleted first, the
+/// other parts are only attempted once it is gone.
+#[derive(Debug, Serialize)]
+pub struct Removal {
+    /// Whether the user had a background to remove
+    pub found: bool,
+    pub dataset: RemovalStep,
+    pub audit: RemovalStep,
+    pub image: RemovalStep,
+    pub staged_copies: RemovalStep,
+    pub cdn: RemovalStep,
+}
+
+impl Removal {
+    pub fn summary(&self) -> String {
+        format!(
+            "usrbg removed\nDataset refresh: {}\nAudit log: {}\nImage: {}\nStaged copies: {}\nCDN purge: {}",
+            self.dataset.describe(),
+            self.audit.describe(),
+            self.image.describe(),
+            self.staged_copies.describe(),
+            self.cdn.describe()
+        )
+    }
+}
+
+/// Removes a user's background: the database entry, its image in the bucket, staged
+/// copies left by resolved requests and the cached CDN copy. The dataset is refreshed and
+/// the removal audited. `action` tells a moderator removal apart from a user removing
+/// their own background.
+pub async fn remove_background(
+    ctx: &Context,
+    uid: &str,
+    actor: UserId,
+    action: AuditAction,
+) -> anyhow::Result<Removal> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    let cdn_purge_configured = data
+        .get::<Config>()
+        .context("Could not get config")?
+        .cdn_purge
+        .is_some();
+    drop(data);
+
+    let existing = repositories.backgrounds.get(uid).await?;
+    let existing = match existing {
+        Some(existing) if repositories.backgrounds.delete(uid).await? => existing,
+        _ => {
+            return Ok(Removal {
+                found: false,
+                dataset: RemovalStep::Skipped,
+                audit: RemovalStep::Skipped,
+                image: RemovalStep::Skipped,
+                staged_copies: RemovalStep::Skipped,
+                cdn: RemovalStep::Skipped,
+            })
+        }
+    };
+
+    let mut audit_entry = AuditEntry::new(action, actor, uid);
+    audit_entry.before_img = Some(existing.img.clone());
+    let audit = RemovalStep::from_result(repositories.audit_log.append(audit_entry).await);
+    let dataset = RemovalStep::from_result(refresh_and_publish_dataset(ctx).await);
+
+    let mut event_data = WebhookEventData::new(uid);
+    event_data.actor = Some(actor.to_string());
+    event_data.image_url = Some(existing.img.clone());
+    emit_webhook_event(ctx, WebhookEvent::BackgroundRemoved, event_data).await;
+
+    let data = ctx.data.read().await;
+    let image_key = referenced_object_key(
+        data.get::<Config>().context("Could not get config")?,
+        &existing,
+    );
+    drop(data);
+
+    let image = match &image_key {
+        Some(image_key) => {
+            RemovalStep::from_result(delete_image_from_s3_bucket(ctx, image_key).await)
+        }
+        None => RemovalStep::Skipped,
+    };
+
+    // The purge runs in the background, the dataset files are purged when it is published
+    let cdn = match image_key {
+        Some(_) if cdn_purge_configured => match purge_cdn(ctx, vec![existing.img]).await {
+            Ok(()) => RemovalStep::Queued,
+            result => RemovalStep::from_result(result),
+        },
+        _ => RemovalStep::Skipped,
+    };
+
+    let staged_copies = RemovalStep::from_result(delete_resolved_staged_images(ctx, uid).await);
+
+    Ok(Removal {
+        found: true,
+        dataset,
+        audit,
+        image,
+        staged_copies,
+        cdn,
+    })
+}
+
+/// Staged images are normally deleted when a request is resolved, this catches any that
+/// were left behind. Pending requests keep theirs so they can still be approved.
+async fn delete_resolved_staged_images(ctx: &Context, uid: &str) -> anyhow::Result<()> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    let requests = repositories.requests.for_user(uid, None).await?;
+
+    let mut failed = 0;
+    for request in requests {
+        if request.status == RequestStatus::Pending {
+            continue;
+        }
+        let request_message_id = MessageId::new(request.request_message_id.parse()?);
+        let result = delete_staged_image(ctx, request_message_id).await;
+        if result.is_err() {
+            println!("{:?}", result);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        bail!("Could not delete {} staged images", failed);
+    }
+    Ok(())
+}
+
+/// Bans a user, permanently if no duration is given. Replaces any existing ban.
+pub async fn ban_user(
+    ctx: &Context,
+    uid: &str,
+    moderator: UserId,
+    duration: Option<Duration>,
+    reason: Option<String>,
+) -> anyhow::Result<Blacklist> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    ban_and_notify(ctx, &repositories, uid, moderator, duration, reason).await
+}
+
+/// Does the work of `ban_user`
+pub async fn ban_and_notify(
+    effects: &impl ModerationEffects,
+    repositories: &Repositories,
+    uid: &str,
+    moderator: UserId,
+    duration: Option<Duration>,
+    reason: Option<String>,
+) -> anyhow::Result<Blacklist> {
+    let entry = store_ban(repositories, uid, moderator, duration, reason).await?;
+
+    let mut event_data = WebhookEventData::new(uid);
+    event_data.actor = Some(moderator.to_string());
+    event_data.reason = entry.reason.clone();
+    event_data.expires_at = entry
+        .expires_at
+        .map(|expires_at| expires_at.to_rfc3339_string());
+    effects
+        .emit_webhook_event(WebhookEvent::UserBanned, event_data)
+        .await;
+
+    Ok(entry)
+}
+
+/// Stores a ban and audits it
+pub async fn store_ban(
+    repositories: &Repositories,
+    uid: &str,
+    moderator: UserId,
+    duration: Option<Duration>,
+    reason: Option<String>,
+) -> anyhow::Result<Blacklist> {
+    let now = bson::DateTime::now();
+    let expires_at = match duration {
+        Some(duration) => {
+            let expires_at = i64::try_from(duration.as_millis())
+                .ok()
+                .and_then(|millis| now.timestamp_millis().checked_add(millis))
+                .context("Ban duration is too long")?;
+            Some(bson::DateTime::from_millis(expires_at))
+        }
+        None => None,
+    };
+    let entry = Blacklist {
+        uid: uid.to_owned(),
+        reason,
+        moderator: Some(moderator.to_string()),
+        created_at: Some(now),
+        expires_at,
+    };
+    repositories.blacklist.upsert(entry.clone()).await?;
+
+    let mut audit_entry = AuditEntry::new(AuditAction::Ban, moderator, uid);
+    audit_entry.reason = entry.reason.clone();
+    append_audit_entry(repositories, audit_entry).await;
+
+    Ok(entry)
+}
+
+/// Returns whether the user was banned
+pub async fn unban_user(ctx: &Context, uid: &str, moderator: UserId) -> anyhow::Result<bool> {
+    let data = ctx.data.read().await;
+    let repositories = data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(data);
+
+    store_unban(&repositories, uid, moderator).await
+}
+
+/// Deletes a ban and audits it. Returns whether the user was banned.
+pub async fn store_unban(
+    repositories: &Repositories,
+    uid: &str,
+    moderator: UserId,
+) -> anyhow::Result<bool> {
+    let deleted = repositories.blacklist.delete(uid).await?;
+    if deleted {
+        let audit_entry = AuditEntry::new(AuditAction::Unban, moderator, uid);
+        append_audit_entry(repositories, audit_entry).await;
+    }
+
+    Ok(deleted)
+}
+
+// Longest duration a temporary ban can have, anything longer should be permanent
+const MAX_DURATION_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+/// Parses durations like `30m`, `12h`, `7d` or `2w`, longer than zero and up to ten years
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let split_at = duration.len().checked_sub(1)?;
+    if !duration.is_char_boundary(split_at) {
+        return None;
+    }
+    let (amount, unit) = duration.split_at(split_at);
+    let amount: u64 = amount.parse().ok()?;
+    let seconds = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let seconds = amount.checked_mul(seconds)?;
+    // A zero length ban would already have expired
+    if seconds == 0 || seconds > MAX_DURATION_SECS {
+        return None;
+    }
+    Some(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashSet,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use crate::{
+        repositories::{
+            fixtures::{
+                background, moderator, pending_request, CREATED_AT, REQUEST_MESSAGE_ID, UID,
+            },
+            memory, BackgroundRepository,
+        },
+        structs::{AuditFilter, UsrbgChange},
+    };
+
+    async fn audit_log(repositories: &Repositories) -> Vec<AuditEntry> {
+        repositories
+            .audit_log
+            .search(&AuditFilter::default(), 0, None)
+            .await
+            .unwrap()
+    }
+
+    /// The bucket, dataset and webhooks as the moderation flows see them
+    #[derive(Default)]
+    struct StubEffects {
+        /// Requests with a staged image in the bucket
+        staged: Mutex<HashSet<MessageId>>,
+        /// Images downloaded from their original URL
+        uploads: Mutex<Vec<String>>,
+        dataset_changes: AtomicUsize,
+        events: Mutex<Vec<(WebhookEvent, WebhookEventData)>>,
+    }
+
+    impl StubEffects {
+        fn with_staged(request_message_id: u64) -> StubEffects {
+            let effects = StubEffects::default();
+            effects
+                .staged
+                .lock()
+                .unwrap()
+                .insert(MessageId::new(request_message_id));
+            effects
+        }
+
+        fn is_staged(&self, request_message_id: u64) -> bool {
+            self.staged
+                .lock()
+                .unwrap()
+                .contains(&MessageId::new(request_message_id))
+        }
+
+        fn events(&self) -> Vec<WebhookEvent> {
+            let events = self.events.lock().unwrap();
+            events.iter().map(|(event, _)| *event).collect()
+        }
+    }
+
+    fn bucket_url(uid: &str) -> String {
+        format!("https://cdn.example.com/{}", uid)
+    }
+
+    #[async_trait]
+    impl ModerationEffects for StubEffects {
+        async fn storage_key(&self, uid: &str) -> anyhow::Result<String> {
+            Ok(uid.to_owned())
+        }
+
+        async fn promote_staged_image(
+            &self,
+            request_message_id: MessageId,
+            uid: &str,
+        ) -> anyhow::Result<Option<(String, ImageMetadata)>> {
+            if self.staged.lock().unwrap().contains(&request_message_id) {
+                Ok(Some((bucket_url(uid), ImageMetadata::default())))
+            } else {
+                Ok(None)
+            }
+        }
+
+        async fn upload_image(
+            &self,
+            image_url: &str,
+            uid: &str,
+        ) -> anyhow::Result<(String, ImageMetadata)> {
+            self.uploads.lock().unwrap().push(image_url.to_owned());
+            Ok((bucket_url(uid), ImageMetadata::default()))
+        }
+
+        async fn delete_staged_image(&self, request_message_id: MessageId) -> anyhow::Result<()> {
+            self.staged.lock().unwrap().remove(&request_message_id);
+            Ok(())
+        }
+
+        async fn usrbg_changed(&self) {
+            self.dataset_changes.fetch_add(1, Ordering::SeqCst);
+        }
+
+        async fn emit_webhook_event(&self, event: WebhookEvent, data: WebhookEventData) {
+            self.events.lock().unwrap().push((event, data));
+        }
+    }
+
+    /// Records what the request log message shows
+    #[derive(Default)]
+    struct StubRequestLog {
+        titles: Vec<&'static str>,
+        user_request_deleted: AtomicBool,
+    }
+
+    #[async_trait]
+    impl RequestLog for StubRequestLog {
+        async fn show_uploading(&mut self, _image_url: &str) -> anyhow::Result<()> {
+            self.titles.push("Uploading...");
+            Ok(())
+        }
+
+        async fn show_pending(&mut self) -> anyhow::Result<()> {
+            self.titles.push("Request Pending");
+            Ok(())
+        }
+
+        async fn show_approved(&mut self, _image_url: &str) -> anyhow::Result<()> {
+            self.titles.push("Request Approved");
+            Ok(())
+        }
+
+        async fn show_denied(&mut self) -> anyhow::Result<()> {
+            self.titles.push("Request Denied");
+            Ok(())
+        }
+
+        async fn delete_user_request(&self) -> anyhow::Result<()> {
+            self.user_request_deleted.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Fails the first upserts, like a database that is briefly unreachable
+    #[derive(Default)]
+    struct FlakyBackgrounds {
+        inner: memory::MemoryBackgrounds,
+        failures: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BackgroundRepository for FlakyBackgrounds {
+        async fn get(&self, uid: &str) -> anyhow::Result<Option<Usrbg>> {
+            self.inner.get(uid).await
+        }
+
+        async fn all(&self) -> anyhow::Result<Vec<Usrbg>> {
+            self.inner.all().await
+        }
+
+        async fn upsert(&self, entry: Usrbg) -> anyhow::Result<()> {
+            let failures = self.failures.load(Ordering::SeqCst);
+            if failures > 0 {
+                self.failures.store(failures - 1, Ordering::SeqCst);
+                bail!("Database is unreachable");
+            }
+            self.inner.upsert(entry).await
+        }
+
+        async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+            self.inner.delete(uid).await
+        }
+
+        async fn revision(&self) -> anyhow::Result<i64> {
+            self.inner.revision().await
+        }
+
+        async fn changes_since(
+            &self,
+            revision: i64,
+            limit: i64,
+        ) -> anyhow::Result<Vec<UsrbgChange>> {
+            self.inner.changes_since(revision, limit).await
+        }
+    }
+
+    fn staged_image_url() -> String {
+        format!("https://example.com/pending/{}", REQUEST_MESSAGE_ID)
+    }
+
+    #[tokio::test]
+    async fn approving_a_request_updates_discord_and_the_bucket() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let effects = StubEffects::with_staged(REQUEST_MESSAGE_ID);
+        let mut request_log = StubRequestLog::default();
+        let request_message_id = MessageId::new(REQUEST_MESSAGE_ID);
+
+        let img = approve_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            &staged_image_url(),
+            request_message_id,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(img, Some(bucket_url(UID)));
+
+        let stored = repositories.backgrounds.get(UID).await.unwrap().unwrap();
+        assert_eq!(stored.img, bucket_url(UID));
+        assert_eq!(stored.storage_key.as_deref(), Some(UID));
+        assert_eq!(
+            stored.source_message_id,
+            Some(REQUEST_MESSAGE_ID.to_string())
+        );
+        let request = repositories
+            .requests
+            .get(&REQUEST_MESSAGE_ID.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.status, RequestStatus::Approved);
+
+        assert_eq!(request_log.titles, vec!["Uploading...", "Request Approved"]);
+        assert!(request_log.user_request_deleted.load(Ordering::SeqCst));
+        assert!(!effects.is_staged(REQUEST_MESSAGE_ID));
+        assert!(effects.uploads.lock().unwrap().is_empty());
+        assert_eq!(effects.dataset_changes.load(Ordering::SeqCst), 1);
+        assert_eq!(effects.events(), vec![WebhookEvent::RequestApproved]);
+
+        // A second click, or another moderator, finds the request already handled
+        let img = approve_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            &staged_image_url(),
+            request_message_id,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(img, None);
+        let denied = deny_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            request_message_id,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert!(!denied);
+
+        assert_eq!(request_log.titles, vec!["Uploading...", "Request Approved"]);
+        assert_eq!(effects.events(), vec![WebhookEvent::RequestApproved]);
+        let audit_log = audit_log(&repositories).await;
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].action, AuditAction::Approve);
+    }
+
+    #[tokio::test]
+    async fn failed_approvals_can_be_retried() {
+        let backgrounds = FlakyBackgrounds::default();
+        backgrounds.failures.store(1, Ordering::SeqCst);
+        let repositories = Repositories {
+            backgrounds: Arc::new(backgrounds),
+            ..memory::repositories()
+        };
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let effects = StubEffects::with_staged(REQUEST_MESSAGE_ID);
+        let mut request_log = StubRequestLog::default();
+        let request_message_id = MessageId::new(REQUEST_MESSAGE_ID);
+
+        let result = approve_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            &staged_image_url(),
+            request_message_id,
+            moderator(),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The request is back to pending, with its buttons and its staged image
+        assert_eq!(request_log.titles, vec!["Uploading...", "Request Pending"]);
+        assert!(!request_log.user_request_deleted.load(Ordering::SeqCst));
+        assert!(repositories
+            .requests
+            .pending_for_user(UID)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(effects.is_staged(REQUEST_MESSAGE_ID));
+        assert!(repositories.backgrounds.get(UID).await.unwrap().is_none());
+        assert!(effects.events().is_empty());
+        assert!(audit_log(&repositories).await.is_empty());
+
+        let img = approve_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            &staged_image_url(),
+            request_message_id,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(img, Some(bucket_url(UID)));
+        assert_eq!(
+            request_log.titles,
+            vec![
+                "Uploading...",
+                "Request Pending",
+                "Uploading...",
+                "Request Approved"
+            ]
+        );
+        // Promoted from the staged copy again rather than downloaded
+        assert!(effects.uploads.lock().unwrap().is_empty());
+        assert!(!effects.is_staged(REQUEST_MESSAGE_ID));
+    }
+
+    #[tokio::test]
+    async fn requests_made_before_staging_are_uploaded() {
+        let repositories = memory::repositories();
+        let effects = StubEffects::default();
+        let mut request_log = StubRequestLog::default();
+
+        let image_url = "https://cdn.discordapp.com/attachments/1/2/background.png";
+        let img = approve_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            image_url,
+            MessageId::new(REQUEST_MESSAGE_ID),
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(img, Some(bucket_url(UID)));
+        assert_eq!(*effects.uploads.lock().unwrap(), vec![image_url.to_owned()]);
+        assert_eq!(request_log.titles, vec!["Uploading...", "Request Approved"]);
+    }
+
+    #[tokio::test]
+    async fn denying_a_request_updates_discord_and_the_bucket() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let effects = StubEffects::with_staged(REQUEST_MESSAGE_ID);
+        let mut request_log = StubRequestLog::default();
+
+        let denied = deny_logged_request(
+            &effects,
+            &mut request_log,
+            &repositories,
+            UID,
+            MessageId::new(REQUEST_MESSAGE_ID),
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert!(denied);
+
+        assert_eq!(request_log.titles, vec!["Request Denied"]);
+        assert!(request_log.user_request_deleted.load(Ordering::SeqCst));
+        assert!(!effects.is_staged(REQUEST_MESSAGE_ID));
+        assert_eq!(effects.events(), vec![WebhookEvent::RequestDenied]);
+        assert_eq!(effects.dataset_changes.load(Ordering::SeqCst), 0);
+        assert!(repositories.backgrounds.get(UID).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn banning_a_user_notifies_webhooks() {
+        let repositories = memory::repositories();
+        let effects = StubEffects::default();
+
+        let entry = ban_and_notify(
+            &effects,
+            &repositories,
+            UID,
+            moderator(),
+            parse_duration("7d"),
+            Some("spamming links".to_owned()),
+        )
+        .await
+        .unwrap();
+
+        let stored = repositories.blacklist.get(UID).await.unwrap().unwrap();
+        assert!(stored.is_active());
+        assert_eq!(stored.expires_at, entry.expires_at);
+
+        let events = effects.events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        let (event, data) = &events[0];
+        assert_eq!(*event, WebhookEvent::UserBanned);
+        assert_eq!(data.uid, UID);
+        assert_eq!(data.actor, Some(moderator().to_string()));
+        assert_eq!(data.reason.as_deref(), Some("spamming links"));
+        assert_eq!(
+            data.expires_at,
+            entry
+                .expires_at
+                .map(|expires_at| expires_at.to_rfc3339_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn approving_a_request_stores_the_background() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+
+        let claim = claim_request(
+            &repositories,
+            MessageId::new(REQUEST_MESSAGE_ID),
+            RequestStatus::Approved,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        let request = match claim {
+            Claim::Claimed(request) => request,
+            claim => panic!("Expected the request to be claimed, got {:?}", claim),
+        };
+        assert_eq!(request.status, RequestStatus::Approved);
+        assert_eq!(request.decided_by, Some(moderator().to_string()));
+
+        store_background(
+            &repositories,
+            background(UID, "https://example.com/first.png"),
+            AuditAction::Approve,
+            moderator(),
+        )
+        .await
+        .unwrap();
+
+        let stored = repositories.backgrounds.get(UID).await.unwrap().unwrap();
+        assert_eq!(stored.img, "https://example.com/first.png");
+        assert!(repositories
+            .requests
+            .pending_for_user(UID)
+            .await
+            .unwrap()
+            .is_none());
+
+        // A second approval replaces the background and records the one it replaced
+        store_background(
+            &repositories,
+            background(UID, "https://example.com/second.png"),
+            AuditAction::Approve,
+            moderator(),
+        )
+        .await
+        .unwrap();
+
+        let audit_log = audit_log(&repositories).await;
+        assert_eq!(audit_log.len(), 2);
+        assert_eq!(audit_log[0].action, AuditAction::Approve);
+        assert_eq!(
+            audit_log[0].before_img.as_deref(),
+            Some("https://example.com/first.png")
+        );
+        assert_eq!(
+            audit_log[0].after_img.as_deref(),
+            Some("https://example.com/second.png")
+        );
+        assert_eq!(audit_log[1].before_img, None);
+        assert_eq!(audit_log[1].actor, moderator().to_string());
+        assert_eq!(audit_log[1].target, UID);
+    }
+
+    #[tokio::test]
+    async fn denying_a_request_only_resolves_it_once() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let request_message_id = MessageId::new(REQUEST_MESSAGE_ID);
+
+        let claim = store_denial(&repositories, UID, request_message_id, moderator())
+            .await
+            .unwrap();
+        let request = match claim {
+            Claim::Claimed(request) => request,
+            claim => panic!("Expected the request to be claimed, got {:?}", claim),
+        };
+        assert_eq!(request.status, RequestStatus::Denied);
+        assert!(request.decided_at.is_some());
+
+        // Already handled, e.g. by another moderator
+        let claim = store_denial(&repositories, UID, request_message_id, moderator())
+            .await
+            .unwrap();
+        assert!(matches!(claim, Claim::AlreadyHandled));
+
+        assert!(repositories.backgrounds.get(UID).await.unwrap().is_none());
+        let audit_log = audit_log(&repositories).await;
+        assert_eq!(audit_log.len(), 1);
+        assert_eq!(audit_log[0].action, AuditAction::Deny);
+    }
+
+    #[tokio::test]
+    async fn requests_can_only_be_claimed_once() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let request_message_id = MessageId::new(REQUEST_MESSAGE_ID);
+
+        let first = claim_request(
+            &repositories,
+            request_message_id,
+            RequestStatus::Approved,
+            moderator(),
+        );
+        let second = claim_request(
+            &repositories,
+            request_message_id,
+            RequestStatus::Approved,
+            UserId::new(80351110224678913),
+        );
+        let (first, second) = tokio::join!(first, second);
+        let claimed = [first.unwrap(), second.unwrap()]
+            .iter()
+            .filter(|claim| matches!(claim, Claim::Claimed(_)))
+            .count();
+        assert_eq!(claimed, 1);
+
+        // A failed approval reopens the request so it can be claimed again
+        repositories
+            .requests
+            .reopen(&REQUEST_MESSAGE_ID.to_string())
+            .await
+            .unwrap();
+        let request = repositories
+            .requests
+            .pending_for_user(UID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.decided_by, None);
+        let claim = claim_request(
+            &repositories,
+            request_message_id,
+            RequestStatus::Approved,
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(claim, Claim::Claimed(_)));
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_record_are_untracked() {
+        let repositories = memory::repositories();
+
+        let claim = store_denial(
+            &repositories,
+            UID,
+            MessageId::new(REQUEST_MESSAGE_ID),
+            moderator(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(claim, Claim::Untracked));
+        assert_eq!(audit_log(&repositories).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancellations_are_audited_once() {
+        let repositories = memory::repositories();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, CREATED_AT))
+            .await
+            .unwrap();
+        let request_message_id = MessageId::new(REQUEST_MESSAGE_ID);
+
+        let claim = store_cancellation(&repositories, UID, request_message_id)
+            .await
+            .unwrap();
+        let request = match claim {
+            Claim::Claimed(request) => request,
+            claim => panic!("Expected the request to be claimed, got {:?}", claim),
+        };
+        assert_eq!(request.status, RequestStatus::Cancelled);
+        assert_eq!(request.decided_by.as_deref(), Some(UID));
+
+        let claim = store_cancellation(&repositories, UID, request_message_id)
+            .await
+            .unwrap();
+        assert!(matches!(claim, Claim::AlreadyHandled));
+
+        // A request made before requests were stored
+        let claim = store_cancellation(&repositories, UID, MessageId::new(REQUEST_MESSAGE_ID + 1))
+            .await
+            .unwrap();
+        assert!(matches!(claim, Claim::Untracked));
+
+        let audit_log = audit_log(&repositories).await;
+        assert_eq!(audit_log.len(), 2);
+        assert!(audit_log
+            .iter()
+            .all(|entry| entry.action == AuditAction::Cancel && entry.actor == UID));
+    }
+
+    #[tokio::test]
+    async fn banning_and_unbanning_a_user() {
+        let repositories = memory::repositories();
+
+        let duration = parse_duration("7d").unwrap();
+        let entry = store_ban(
+            &repositories,
+            UID,
+            moderator(),
+            Some(duration),
+            Some("spamming links".to_owned()),
+        )
+        .await
+        .unwrap();
+        let expires_in = entry.expires_at.unwrap().timestamp_millis()
+            - entry.created_at.unwrap().timestamp_millis();
+        assert_eq!(expires_in, duration.as_millis() as i64);
+
+        let stored = repositories.blacklist.get(UID).await.unwrap().unwrap();
+        assert!(stored.is_active());
+        assert_eq!(stored.reason.as_deref(), Some("spamming links"));
+
+        assert!(store_unban(&repositories, UID, moderator()).await.unwrap());
+        assert!(!store_unban(&repositories, UID, moderator()).await.unwrap());
+        assert!(repositories.blacklist.get(UID).await.unwrap().is_none());
+
+        let audit_log = audit_log(&repositories).await;
+        let actions: Vec<AuditAction> = audit_log.iter().map(|entry| entry.action).collect();
+        assert_eq!(actions, vec![AuditAction::Unban, AuditAction::Ban]);
+        assert_eq!(audit_log[1].reason.as_deref(), Some("spamming links"));
+    }
+
+    #[tokio::test]
+    async fn permanent_bans_do_not_expire() {
+        let repositories = memory::repositories();
+
+        let entry = store_ban(&repositories, UID, moderator(), None, None)
+            .await
+            .unwrap();
+        assert!(entry.expires_at.is_none());
+        assert_eq!(repositories.blacklist.delete_expired().await.unwrap(), 0);
+        assert!(repositories.blacklist.get(UID).await.unwrap().is_some());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(
+            parse_duration("2w"),
+            Some(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("7"), None);
+        assert_eq!(parse_duration("d"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("0d"), None);
+    }
+
+    #[test]
+    fn rejects_absurd_durations() {
+        assert_eq!(
+            parse_duration("520w"),
+            Some(Duration::from_secs(520 * 7 * 24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("600w"), None);
+        assert_eq!(parse_duration("18446744073709551615w"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bans_that_would_overflow() {
+        let repositories = memory::repositories();
+
+        let result = store_ban(
+            &repositories,
+            UID,
+            moderator(),
+            Some(Duration::from_secs(u64::MAX)),
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(repositories.blacklist.get(UID).await.unwrap().is_none());
+    }
+}
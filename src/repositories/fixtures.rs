@@ -0,0 +1,58 @@
+// Records shared by the tests of the repositories and of the code built on them
+
+use serenity::all::UserId;
+
+use crate::structs::{BackgroundRequest, Blacklist, ImageMetadata, RequestStatus, Usrbg};
+
+pub const UID: &str = "80351110224678912";
+pub const OTHER_UID: &str = "80351110224678913";
+pub const REQUEST_MESSAGE_ID: u64 = 1031275431413313566;
+// Somewhere in 2023, so records created with it are never new enough to matter for expiry
+pub const CREATED_AT: i64 = 1_700_000_000_000;
+
+pub fn moderator() -> UserId {
+    UserId::new(125227483518861312)
+}
+
+pub fn background(uid: &str, img: &str) -> Usrbg {
+    Usrbg {
+        uid: uid.to_owned(),
+        img: img.to_owned(),
+        approved_by: Some(moderator().to_string()),
+        approved_at: Some(bson::DateTime::from_millis(CREATED_AT)),
+        source_message_id: None,
+        storage_key: None,
+        image: ImageMetadata {
+            content_hash: Some("ab".repeat(32)),
+            width: Some(1920),
+            height: Some(1080),
+            byte_size: Some(123_456),
+            mime_type: Some("image/png".to_owned()),
+        },
+    }
+}
+
+pub fn pending_request(request_message_id: u64, uid: &str, created_at: i64) -> BackgroundRequest {
+    BackgroundRequest {
+        uid: uid.to_owned(),
+        request_message_id: request_message_id.to_string(),
+        // Logged right after the request was made
+        log_message_id: (request_message_id + 1).to_string(),
+        image_url: format!("https://example.com/pending/{}", request_message_id),
+        image: ImageMetadata::default(),
+        status: RequestStatus::Pending,
+        created_at: bson::DateTime::from_millis(created_at),
+        decided_at: None,
+        decided_by: None,
+    }
+}
+
+pub fn ban(uid: &str, expires_at: Option<i64>) -> Blacklist {
+    Blacklist {
+        uid: uid.to_owned(),
+        reason: Some("spamming links".to_owned()),
+        moderator: Some(moderator().to_string()),
+        created_at: Some(bson::DateTime::now()),
+        expires_at: expires_at.map(bson::DateTime::from_millis),
+    }
+}
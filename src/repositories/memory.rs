@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serenity::async_trait;
+
+use crate::{
+    repositories::{
+        AuditLogRepository, BackgroundRepository, BlacklistRepository, RequestRepository,
+        SchemaRepository, WebhookDeadLetterRepository,
+    },
+    structs::{
+        AuditEntry, AuditFilter, BackgroundRequest, Blacklist, ChangeOp, DuplicateUids,
+        Repositories, RequestStatus, Usrbg, UsrbgChange, WebhookDeadLetter,
+    },
+};
+
+// In-memory repositories, for running the bot without a database server and for tests.
+// Nothing is persisted across restarts.
+
+pub fn repositories() -> Repositories {
+    Repositories {
+        backgrounds: Arc::new(MemoryBackgrounds::default()),
+        blacklist: Arc::new(MemoryBlacklist::default()),
+        requests: Arc::new(MemoryRequests::default()),
+        audit_log: Arc::new(MemoryAuditLog::default()),
+        dead_letters: Arc::new(MemoryDeadLetters::default()),
+        schema: Arc::new(MemorySchema::default()),
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBackgrounds {
+    entries: Mutex<HashMap<String, Usrbg>>,
+    changes: Mutex<Vec<UsrbgChange>>,
+}
+
+impl MemoryBackgrounds {
+    fn record_change(&self, uid: &str, op: ChangeOp, img: Option<String>) {
+        let mut changes = self.changes.lock().unwrap();
+        let revision = changes.len() as i64 + 1;
+        changes.push(UsrbgChange {
+            revision,
+            uid: uid.to_owned(),
+            op,
+            img,
+        });
+    }
+}
+
+#[async_trait]
+impl BackgroundRepository for MemoryBackgrounds {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Usrbg>> {
+        Ok(self.entries.lock().unwrap().get(uid).cloned())
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Usrbg>> {
+        Ok(self.entries.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn upsert(&self, entry: Usrbg) -> anyhow::Result<()> {
+        self.record_change(&entry.uid, ChangeOp::Upsert, Some(entry.img.clone()));
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.uid.clone(), entry);
+        Ok(())
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let deleted = self.entries.lock().unwrap().remove(uid).is_some();
+        if deleted {
+            self.record_change(uid, ChangeOp::Delete, None);
+        }
+        Ok(deleted)
+    }
+
+    async fn revision(&self) -> anyhow::Result<i64> {
+        Ok(self.changes.lock().unwrap().len() as i64)
+    }
+
+    async fn changes_since(&self, revision: i64, limit: i64) -> anyhow::Result<Vec<UsrbgChange>> {
+        Ok(self
+            .changes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|change| change.revision > revision)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryBlacklist {
+    entries: Mutex<HashMap<String, Blacklist>>,
+}
+
+#[async_trait]
+impl BlacklistRepository for MemoryBlacklist {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Blacklist>> {
+        Ok(self.entries.lock().unwrap().get(uid).cloned())
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Blacklist>> {
+        Ok(self.entries.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn upsert(&self, entry: Blacklist) -> anyhow::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.uid.clone(), entry);
+        Ok(())
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        Ok(self.entries.lock().unwrap().remove(uid).is_some())
+    }
+
+    async fn delete_expired(&self) -> anyhow::Result<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, entry| entry.is_active());
+        Ok((before - entries.len()) as u64)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRequests {
+    requests: Mutex<HashMap<String, BackgroundRequest>>,
+}
+
+#[async_trait]
+impl RequestRepository for MemoryRequests {
+    async fn create(&self, request: BackgroundRequest) -> anyhow::Result<()> {
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(request.request_message_id.clone(), request);
+        Ok(())
+    }
+
+    async fn get(&self, request_message_id: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        Ok(self
+            .requests
+            .lock()
+            .unwrap()
+            .get(request_message_id)
+            .cloned())
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        Ok(self.requests.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn pending_for_user(&self, uid: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        Ok(self
+            .requests
+            .lock()
+            .unwrap()
+            .values()
+            .find(|request| request.uid == uid && request.status == RequestStatus::Pending)
+            .cloned())
+    }
+
+    async fn pending(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let mut pending: Vec<BackgroundRequest> = self
+            .requests
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|request| request.status == RequestStatus::Pending)
+            .cloned()
+            .collect();
+        pending.sort_by_key(|request| request.created_at);
+        Ok(pending)
+    }
+
+    async fn count_pending(&self) -> anyhow::Result<u64> {
+        Ok(self
+            .requests
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|request| request.status == RequestStatus::Pending)
+            .count() as u64)
+    }
+
+    async fn for_user(
+        &self,
+        uid: &str,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let mut requests: Vec<BackgroundRequest> = self
+            .requests
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|request| request.uid == uid)
+            .cloned()
+            .collect();
+        requests.sort_by_key(|request| std::cmp::Reverse(request.created_at));
+        if let Some(limit) = limit {
+            requests.truncate(limit as usize);
+        }
+        Ok(requests)
+    }
+
+    async fn resolve(
+        &self,
+        request_message_id: &str,
+        status: RequestStatus,
+        decided_by: Option<String>,
+    ) -> anyhow::Result<Option<BackgroundRequest>> {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.get_mut(request_message_id) {
+            Some(request) if request.status == RequestStatus::Pending => {
+                request.status = status;
+                request.decided_at = Some(bson::DateTime::now());
+                request.decided_by = decided_by;
+                Ok(Some(request.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn reopen(&self, request_message_id: &str) -> anyhow::Result<()> {
+        if let Some(request) = self.requests.lock().unwrap().get_mut(request_message_id) {
+            request.status = RequestStatus::Pending;
+            request.decided_at = None;
+            request.decided_by = None;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryAuditLog {
+    /// Oldest entry first
+    entries: Mutex<Vec<AuditEntry>>,
+}
+
+#[async_trait]
+impl AuditLogRepository for MemoryAuditLog {
+    async fn append(&self, entry: AuditEntry) -> anyhow::Result<()> {
+        self.entries.lock().unwrap().push(entry);
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .skip(skip as usize)
+            .take(limit.map_or(usize::MAX, |limit| limit as usize))
+            .cloned()
+            .collect())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryDeadLetters {
+    dead_letters: Mutex<Vec<WebhookDeadLetter>>,
+}
+
+#[async_trait]
+impl WebhookDeadLetterRepository for MemoryDeadLetters {
+    async fn append(&self, dead_letter: WebhookDeadLetter) -> anyhow::Result<()> {
+        self.dead_letters.lock().unwrap().push(dead_letter);
+        Ok(())
+    }
+}
+
+/// Entries are keyed by uid, so there is nothing to index and duplicates cannot exist
+#[derive(Default)]
+pub struct MemorySchema {
+    versions: Mutex<HashMap<String, u32>>,
+}
+
+#[async_trait]
+impl SchemaRepository for MemorySchema {
+    async fn ensure_indexes(&self) -> anyhow::Result<Vec<DuplicateUids>> {
+        Ok(vec![])
+    }
+
+    async fn resolve_duplicates(&self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    async fn schema_version(&self, collection: &str) -> anyhow::Result<u32> {
+        Ok(self
+            .versions
+            .lock()
+            .unwrap()
+            .get(collection)
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn set_schema_version(&self, collection: &str, version: u32) -> anyhow::Result<()> {
+        self.versions
+            .lock()
+            .unwrap()
+            .insert(collection.to_owned(), version);
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub(crate) mod memory;
+pub(crate) mod mongo;
+pub(crate) mod sql;
+
+use anyhow::bail;
+use serenity::async_trait;
+
+use crate::migrations::USRBG_SCHEMA;
+use crate::structs::{
+    AuditEntry, AuditFilter, BackgroundRequest, Blacklist, Config, DatabaseBackend, DuplicateUids,
+    Repositories, RequestStatus, Usrbg, UsrbgChange, WebhookDeadLetter,
+};
+
+#[async_trait]
+pub trait BackgroundRepository: Send + Sync {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Usrbg>>;
+    async fn all(&self) -> anyhow::Result<Vec<Usrbg>>;
+    /// Stores the entry and appends the change to the change log
+    async fn upsert(&self, entry: Usrbg) -> anyhow::Result<()>;
+    /// Returns whether an entry was deleted. Deletions are appended to the change log.
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool>;
+    /// Latest change log revision, 0 before the first change
+    async fn revision(&self) -> anyhow::Result<i64>;
+    /// Changes after the given revision, oldest first
+    async fn changes_since(&self, revision: i64, limit: i64) -> anyhow::Result<Vec<UsrbgChange>>;
+}
+
+#[async_trait]
+pub trait BlacklistRepository: Send + Sync {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Blacklist>>;
+    async fn all(&self) -> anyhow::Result<Vec<Blacklist>>;
+    async fn upsert(&self, entry: Blacklist) -> anyhow::Result<()>;
+    /// Returns whether an entry was deleted
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool>;
+    /// Deletes temporary bans that have run out, returning how many were deleted
+    async fn delete_expired(&self) -> anyhow::Result<u64>;
+}
+
+#[async_trait]
+pub trait RequestRepository: Send + Sync {
+    async fn create(&self, request: BackgroundRequest) -> anyhow::Result<()>;
+    async fn get(&self, request_message_id: &str) -> anyhow::Result<Option<BackgroundRequest>>;
+    async fn all(&self) -> anyhow::Result<Vec<BackgroundRequest>>;
+    async fn pending_for_user(&self, uid: &str) -> anyhow::Result<Option<BackgroundRequest>>;
+    /// Every pending request, oldest first
+    async fn pending(&self) -> anyhow::Result<Vec<BackgroundRequest>>;
+    async fn count_pending(&self) -> anyhow::Result<u64>;
+    /// The most recent requests made by a user, newest first. Every request when `limit`
+    /// is `None`.
+    async fn for_user(
+        &self,
+        uid: &str,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<BackgroundRequest>>;
+    /// Moves a pending request to its final status. Returns the updated request, or `None`
+    /// if there was no pending request with that id (e.g. it was already handled).
+    async fn resolve(
+        &self,
+        request_message_id: &str,
+        status: RequestStatus,
+        decided_by: Option<String>,
+    ) -> anyhow::Result<Option<BackgroundRequest>>;
+    /// Moves a resolved request back to pending, for when acting on it failed
+    async fn reopen(&self, request_message_id: &str) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+pub trait AuditLogRepository: Send + Sync {
+    async fn append(&self, entry: AuditEntry) -> anyhow::Result<()>;
+    /// Entries matching the filter, newest first. A `limit` of `None` returns every match.
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<AuditEntry>>;
+}
+
+#[async_trait]
+pub trait WebhookDeadLetterRepository: Send + Sync {
+    async fn append(&self, dead_letter: WebhookDeadLetter) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+pub trait SchemaRepository: Send + Sync {
+    /// Creates the indexes every collection needs. Unique indexes are skipped for
+    /// collections that already contain duplicates, which are returned instead.
+    async fn ensure_indexes(&self) -> anyhow::Result<Vec<DuplicateUids>>;
+    /// Deletes all but the newest entry for every duplicated uid, returning how many
+    /// entries were deleted.
+    async fn resolve_duplicates(&self) -> anyhow::Result<u64>;
+    /// Schema version a collection has been migrated to, 0 if it was never migrated
+    async fn schema_version(&self, collection: &str) -> anyhow::Result<u32>;
+    async fn set_schema_version(&self, collection: &str, version: u32) -> anyhow::Result<()>;
+}
+
+pub async fn connect_repositories(config: &Config) -> anyhow::Result<Repositories> {
+    match config.database.backend() {
+        DatabaseBackend::Mongodb => mongo::connect(config).await,
+        DatabaseBackend::Sqlite | DatabaseBackend::Postgres => sql::connect(config).await,
+        DatabaseBackend::Memory => Ok(memory::repositories()),
+    }
+}
+
+/// Copies every entry from one set of repositories into another, e.g. from an existing
+/// Mongo deployment into a SQL database. Refuses to copy into repositories that already
+/// contain backgrounds or bans.
+pub async fn copy_repositories(source: &Repositories, target: &Repositories) -> anyhow::Result<()> {
+    if !target.backgrounds.all().await?.is_empty() || !target.blacklist.all().await?.is_empty() {
+        bail!("Target database is not empty");
+    }
+
+    let backgrounds = source.backgrounds.all().await?;
+    println!("Copying {} usrbg entries", backgrounds.len());
+    for entry in backgrounds {
+        target.backgrounds.upsert(entry).await?;
+    }
+
+    let blacklist = source.blacklist.all().await?;
+    println!("Copying {} blacklist entries", blacklist.len());
+    for entry in blacklist {
+        target.blacklist.upsert(entry).await?;
+    }
+
+    let requests = source.requests.all().await?;
+    println!("Copying {} requests", requests.len());
+    for request in requests {
+        target.requests.create(request).await?;
+    }
+
+    // Appended oldest first so the copy keeps the original order
+    let mut audit_log = source
+        .audit_log
+        .search(&AuditFilter::default(), 0, None)
+        .await?;
+    audit_log.reverse();
+    println!("Copying {} audit log entries", audit_log.len());
+    for entry in audit_log {
+        target.audit_log.append(entry).await?;
+    }
+
+    let version = source.schema.schema_version(USRBG_SCHEMA).await?;
+    target
+        .schema
+        .set_schema_version(USRBG_SCHEMA, version)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,698 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use bson::{doc, Document};
+use futures_util::TryStreamExt;
+use mongodb::{
+    error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    options::{
+        FindOneAndReplaceOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions,
+        ReturnDocument, UpdateOptions,
+    },
+    Client, ClientSession, Collection, IndexModel,
+};
+use serenity::async_trait;
+
+use crate::{
+    database,
+    repositories::{
+        AuditLogRepository, BackgroundRepository, BlacklistRepository, RequestRepository,
+        SchemaRepository, WebhookDeadLetterRepository,
+    },
+    structs::{
+        AuditEntry, AuditFilter, BackgroundRequest, Blacklist, ChangeOp, Config, DuplicateUids,
+        Repositories, RequestStatus, Usrbg, UsrbgChange, WebhookDeadLetter,
+    },
+};
+
+pub async fn connect(config: &Config) -> anyhow::Result<Repositories> {
+    connect_to(config, &config.database.url, &config.database.name).await
+}
+
+/// Connects to a database other than the configured one, using the configured collection names
+pub async fn connect_to(config: &Config, url: &str, name: &str) -> anyhow::Result<Repositories> {
+    let client = database::connect_raw_client(url).await?;
+    let db = client.database(name);
+    let transactions = supports_transactions(&client).await;
+    if !transactions {
+        println!("MongoDB is a standalone server, usrbg changes are written without transactions. Use a replica set (a single node one is enough) so the change log always commits in revision order.");
+    }
+    Ok(Repositories {
+        backgrounds: Arc::new(MongoBackgrounds {
+            client: client.clone(),
+            transactions,
+            collection: db.collection(&config.database.usrbg_collection),
+            changes: db.collection(&config.database.changes_collection),
+            meta: db.collection(&config.database.meta_collection),
+        }),
+        blacklist: Arc::new(MongoBlacklist {
+            collection: db.collection(&config.database.blacklist_collection),
+        }),
+        requests: Arc::new(MongoRequests {
+            collection: db.collection(&config.database.requests_collection),
+        }),
+        audit_log: Arc::new(MongoAuditLog {
+            collection: db.collection(&config.database.audit_log_collection),
+        }),
+        dead_letters: Arc::new(MongoDeadLetters {
+            collection: db.collection(&config.database.dead_letters_collection),
+        }),
+        schema: Arc::new(MongoSchema {
+            uid_collections: vec![
+                db.collection(&config.database.usrbg_collection),
+                db.collection(&config.database.blacklist_collection),
+            ],
+            requests: db.collection(&config.database.requests_collection),
+            audit_log: db.collection(&config.database.audit_log_collection),
+            changes: db.collection(&config.database.changes_collection),
+            meta: db.collection(&config.database.meta_collection),
+        }),
+    })
+}
+
+/// Transactions need a replica set or a sharded cluster, standalone servers do not support them
+async fn supports_transactions(client: &Client) -> bool {
+    let hello = client
+        .database("admin")
+        .run_command(doc! { "hello": 1 }, None)
+        .await;
+    match hello {
+        Ok(hello) => hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"),
+        // Servers too old to know `hello` are treated as standalone
+        Err(err) => {
+            println!("{:?}", err);
+            false
+        }
+    }
+}
+
+// Id of the meta document holding the latest change log revision
+const REVISION_COUNTER: &str = "usrbg_revision";
+// Transactions that conflict with another change are retried this many times
+const TRANSACTION_ATTEMPTS: u32 = 10;
+
+/// Every change is written in a transaction together with its change log entry when the
+/// database is a replica set. On a standalone server they are written one after the other,
+/// so a reader can briefly miss a change whose revision another change has already passed.
+pub struct MongoBackgrounds {
+    client: Client,
+    transactions: bool,
+    collection: Collection<Usrbg>,
+    changes: Collection<UsrbgChange>,
+    meta: Collection<Document>,
+}
+
+impl MongoBackgrounds {
+    /// Appends a change in the session, inside its transaction if there is one. Concurrent
+    /// transactions conflict on the revision counter, so revisions are committed in order
+    /// and without gaps.
+    async fn record_change(
+        &self,
+        session: &mut ClientSession,
+        uid: &str,
+        op: ChangeOp,
+        img: Option<String>,
+    ) -> anyhow::Result<()> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(Some(true))
+            .return_document(Some(ReturnDocument::After))
+            .build();
+
+        let counter = self
+            .meta
+            .find_one_and_update_with_session(
+                doc! { "_id": REVISION_COUNTER },
+                doc! { "$inc": { "revision": 1_i64 } },
+                Some(options),
+                session,
+            )
+            .await
+            .context("Could not increment revision")?
+            .context("Revision counter is missing")?;
+
+        self.changes
+            .insert_one_with_session(
+                UsrbgChange {
+                    revision: counter.get_i64("revision")?,
+                    uid: uid.to_owned(),
+                    op,
+                    img,
+                },
+                None,
+                session,
+            )
+            .await
+            .context("Could not insert change")?;
+        Ok(())
+    }
+
+    async fn upsert_in_session(
+        &self,
+        session: &mut ClientSession,
+        entry: &Usrbg,
+    ) -> anyhow::Result<()> {
+        // Replaced rather than updated, so fields the new entry leaves unset (e.g. the
+        // dimensions of an image in an unrecognised format) do not keep their old values
+        let options = FindOneAndReplaceOptions::builder()
+            .upsert(Some(true))
+            .build();
+
+        self.collection
+            .find_one_and_replace_with_session(
+                doc! { "uid": &entry.uid },
+                entry,
+                Some(options),
+                session,
+            )
+            .await
+            .context("Could not upsert usrbg entry")?;
+        self.record_change(
+            session,
+            &entry.uid,
+            ChangeOp::Upsert,
+            Some(entry.img.clone()),
+        )
+        .await
+    }
+
+    async fn delete_in_session(
+        &self,
+        session: &mut ClientSession,
+        uid: &str,
+    ) -> anyhow::Result<bool> {
+        let result = self
+            .collection
+            .delete_one_with_session(doc! { "uid": uid }, None, session)
+            .await
+            .context("Could not delete usrbg entry")?;
+        let deleted = result.deleted_count > 0;
+        if deleted {
+            self.record_change(session, uid, ChangeOp::Delete, None)
+                .await?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Commits the session's transaction if `result` is ok, otherwise aborts it. Returns `None`
+/// if the transaction hit a transient error, e.g. a conflict with another change, and
+/// should be retried.
+async fn finish_transaction<T>(
+    session: &mut ClientSession,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    let value = match result {
+        Ok(value) => value,
+        Err(err) => {
+            let result = session.abort_transaction().await;
+            if result.is_err() {
+                println!("{:?}", result);
+            }
+            let transient = err
+                .downcast_ref::<mongodb::error::Error>()
+                .is_some_and(|err| err.contains_label(TRANSIENT_TRANSACTION_ERROR));
+            if transient {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+    };
+
+    loop {
+        match session.commit_transaction().await {
+            Ok(()) => return Ok(Some(value)),
+            Err(err) if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) => continue,
+            Err(err) if err.contains_label(TRANSIENT_TRANSACTION_ERROR) => return Ok(None),
+            Err(err) => return Err(err).context("Could not commit change"),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundRepository for MongoBackgrounds {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Usrbg>> {
+        self.collection
+            .find_one(doc! { "uid": uid }, None)
+            .await
+            .context("Could not complete usrbg search")
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Usrbg>> {
+        database::find_all(&self.collection)
+            .await
+            .context("Could not list usrbg entries")
+    }
+
+    async fn upsert(&self, entry: Usrbg) -> anyhow::Result<()> {
+        let mut session = self.client.start_session(None).await?;
+        if !self.transactions {
+            return self.upsert_in_session(&mut session, &entry).await;
+        }
+        for _ in 0..TRANSACTION_ATTEMPTS {
+            session.start_transaction(None).await?;
+            let result = self.upsert_in_session(&mut session, &entry).await;
+            let result = finish_transaction(&mut session, result).await?;
+            if let Some(()) = result {
+                return Ok(());
+            }
+        }
+        bail!("Could not upsert usrbg entry, too many conflicting changes")
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let mut session = self.client.start_session(None).await?;
+        if !self.transactions {
+            return self.delete_in_session(&mut session, uid).await;
+        }
+        for _ in 0..TRANSACTION_ATTEMPTS {
+            session.start_transaction(None).await?;
+            let result = self.delete_in_session(&mut session, uid).await;
+            let result = finish_transaction(&mut session, result).await?;
+            if let Some(deleted) = result {
+                return Ok(deleted);
+            }
+        }
+        bail!("Could not delete usrbg entry, too many conflicting changes")
+    }
+
+    async fn revision(&self) -> anyhow::Result<i64> {
+        let counter = self
+            .meta
+            .find_one(doc! { "_id": REVISION_COUNTER }, None)
+            .await
+            .context("Could not get revision")?;
+
+        match counter {
+            Some(counter) => Ok(counter.get_i64("revision")?),
+            None => Ok(0),
+        }
+    }
+
+    async fn changes_since(&self, revision: i64, limit: i64) -> anyhow::Result<Vec<UsrbgChange>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "revision": 1 })
+            .limit(limit)
+            .build();
+
+        self.changes
+            .find(doc! { "revision": { "$gt": revision } }, options)
+            .await?
+            .try_collect()
+            .await
+            .context("Could not list changes")
+    }
+}
+
+pub struct MongoBlacklist {
+    collection: Collection<Blacklist>,
+}
+
+#[async_trait]
+impl BlacklistRepository for MongoBlacklist {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Blacklist>> {
+        self.collection
+            .find_one(doc! { "uid": uid }, None)
+            .await
+            .context("Could not complete blacklist search")
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Blacklist>> {
+        database::find_all(&self.collection)
+            .await
+            .context("Could not list blacklist entries")
+    }
+
+    async fn upsert(&self, entry: Blacklist) -> anyhow::Result<()> {
+        database::upsert(&self.collection, &entry.uid.clone(), entry)
+            .await
+            .context("Could not upsert blacklist entry")?;
+        Ok(())
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let result = database::delete(&self.collection, uid.to_owned())
+            .await
+            .context("Could not delete blacklist entry")?;
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn delete_expired(&self) -> anyhow::Result<u64> {
+        let result = self
+            .collection
+            .delete_many(
+                doc! { "expires_at": { "$lte": bson::DateTime::now() } },
+                None,
+            )
+            .await
+            .context("Could not delete expired blacklist entries")?;
+        Ok(result.deleted_count)
+    }
+}
+
+pub struct MongoRequests {
+    collection: Collection<BackgroundRequest>,
+}
+
+#[async_trait]
+impl RequestRepository for MongoRequests {
+    async fn create(&self, request: BackgroundRequest) -> anyhow::Result<()> {
+        self.collection
+            .insert_one(request, None)
+            .await
+            .context("Could not insert request")?;
+        Ok(())
+    }
+
+    async fn get(&self, request_message_id: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        self.collection
+            .find_one(doc! { "request_message_id": request_message_id }, None)
+            .await
+            .context("Could not find request")
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        database::find_all(&self.collection)
+            .await
+            .context("Could not list requests")
+    }
+
+    async fn pending_for_user(&self, uid: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        self.collection
+            .find_one(
+                doc! { "uid": uid, "status": bson::to_bson(&RequestStatus::Pending)? },
+                None,
+            )
+            .await
+            .context("Could not find pending request")
+    }
+
+    async fn pending(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": 1 })
+            .build();
+
+        self.collection
+            .find(
+                doc! { "status": bson::to_bson(&RequestStatus::Pending)? },
+                options,
+            )
+            .await?
+            .try_collect()
+            .await
+            .context("Could not get pending requests")
+    }
+
+    async fn count_pending(&self) -> anyhow::Result<u64> {
+        self.collection
+            .count_documents(
+                doc! { "status": bson::to_bson(&RequestStatus::Pending)? },
+                None,
+            )
+            .await
+            .context("Could not count pending requests")
+    }
+
+    async fn for_user(
+        &self,
+        uid: &str,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .limit(limit.map(|limit| limit as i64))
+            .build();
+
+        self.collection
+            .find(doc! { "uid": uid }, options)
+            .await?
+            .try_collect()
+            .await
+            .context("Could not get requests for user")
+    }
+
+    async fn resolve(
+        &self,
+        request_message_id: &str,
+        status: RequestStatus,
+        decided_by: Option<String>,
+    ) -> anyhow::Result<Option<BackgroundRequest>> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(Some(ReturnDocument::After))
+            .build();
+
+        self.collection
+            .find_one_and_update(
+                doc! {
+                    "request_message_id": request_message_id,
+                    "status": bson::to_bson(&RequestStatus::Pending)?,
+                },
+                doc! { "$set": {
+                    "status": bson::to_bson(&status)?,
+                    "decided_at": bson::DateTime::now(),
+                    "decided_by": decided_by,
+                } },
+                Some(options),
+            )
+            .await
+            .context("Could not update request")
+    }
+
+    async fn reopen(&self, request_message_id: &str) -> anyhow::Result<()> {
+        self.collection
+            .update_one(
+                doc! { "request_message_id": request_message_id },
+                doc! { "$set": {
+                    "status": bson::to_bson(&RequestStatus::Pending)?,
+                    "decided_at": null,
+                    "decided_by": null,
+                } },
+                None,
+            )
+            .await
+            .context("Could not reopen request")?;
+        Ok(())
+    }
+}
+
+pub struct MongoAuditLog {
+    collection: Collection<AuditEntry>,
+}
+
+#[async_trait]
+impl AuditLogRepository for MongoAuditLog {
+    async fn append(&self, entry: AuditEntry) -> anyhow::Result<()> {
+        self.collection
+            .insert_one(entry, None)
+            .await
+            .context("Could not insert audit log entry")?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        let mut query = doc! {};
+        if let Some(target) = &filter.target {
+            query.insert("target", target);
+        }
+        if let Some(actor) = &filter.actor {
+            query.insert("actor", actor);
+        }
+        if let Some(action) = &filter.action {
+            query.insert("action", bson::to_bson(action)?);
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! { "created_at": -1 })
+            .skip(skip)
+            .limit(limit.map(|limit| limit as i64))
+            .build();
+
+        self.collection
+            .find(query, options)
+            .await?
+            .try_collect()
+            .await
+            .context("Could not search audit log")
+    }
+}
+
+pub struct MongoDeadLetters {
+    collection: Collection<WebhookDeadLetter>,
+}
+
+#[async_trait]
+impl WebhookDeadLetterRepository for MongoDeadLetters {
+    async fn append(&self, dead_letter: WebhookDeadLetter) -> anyhow::Result<()> {
+        self.collection
+            .insert_one(dead_letter, None)
+            .await
+            .context("Could not insert webhook dead letter")?;
+        Ok(())
+    }
+}
+
+pub struct MongoSchema {
+    /// Collections keyed by a unique uid
+    uid_collections: Vec<Collection<Document>>,
+    requests: Collection<Document>,
+    audit_log: Collection<Document>,
+    changes: Collection<Document>,
+    meta: Collection<Document>,
+}
+
+impl MongoSchema {
+    async fn duplicate_uids(collection: &Collection<Document>) -> anyhow::Result<Vec<String>> {
+        let pipeline = vec![
+            doc! { "$group": { "_id": "$uid", "count": { "$sum": 1 } } },
+            doc! { "$match": { "count": { "$gt": 1 } } },
+        ];
+
+        let groups: Vec<Document> = collection
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await
+            .context("Could not search for duplicate uids")?;
+
+        Ok(groups
+            .iter()
+            .filter_map(|group| group.get_str("_id").ok())
+            .map(|uid| uid.to_owned())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl SchemaRepository for MongoSchema {
+    async fn ensure_indexes(&self) -> anyhow::Result<Vec<DuplicateUids>> {
+        let mut duplicates = vec![];
+
+        for collection in &self.uid_collections {
+            let uids = Self::duplicate_uids(collection).await?;
+            if !uids.is_empty() {
+                duplicates.push(DuplicateUids {
+                    collection: collection.name().to_owned(),
+                    uids,
+                });
+                continue;
+            }
+
+            let index = IndexModel::builder()
+                .keys(doc! { "uid": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            collection
+                .create_index(index, None)
+                .await
+                .with_context(|| format!("Could not create uid index on {}", collection.name()))?;
+        }
+
+        let request_indexes = vec![
+            IndexModel::builder()
+                .keys(doc! { "request_message_id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "status": 1, "uid": 1 })
+                .build(),
+            IndexModel::builder().keys(doc! { "created_at": 1 }).build(),
+            IndexModel::builder()
+                .keys(doc! { "uid": 1, "created_at": -1 })
+                .build(),
+        ];
+        self.requests
+            .create_indexes(request_indexes, None)
+            .await
+            .context("Could not create request indexes")?;
+
+        let audit_log_indexes = vec![
+            IndexModel::builder()
+                .keys(doc! { "created_at": -1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "target": 1, "created_at": -1 })
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "actor": 1, "created_at": -1 })
+                .build(),
+        ];
+        self.audit_log
+            .create_indexes(audit_log_indexes, None)
+            .await
+            .context("Could not create audit log indexes")?;
+
+        let changes_index = IndexModel::builder()
+            .keys(doc! { "revision": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.changes
+            .create_index(changes_index, None)
+            .await
+            .context("Could not create change log index")?;
+
+        Ok(duplicates)
+    }
+
+    async fn resolve_duplicates(&self) -> anyhow::Result<u64> {
+        let mut deleted = 0;
+
+        for collection in &self.uid_collections {
+            for uid in Self::duplicate_uids(collection).await? {
+                // ObjectIds start with their creation time, so the first entry is the newest
+                let options = FindOptions::builder().sort(doc! { "_id": -1 }).build();
+                let entries: Vec<Document> = collection
+                    .find(doc! { "uid": &uid }, options)
+                    .await?
+                    .try_collect()
+                    .await?;
+
+                let stale_ids: Vec<_> = entries
+                    .iter()
+                    .skip(1)
+                    .filter_map(|entry| entry.get("_id").cloned())
+                    .collect();
+
+                let result = collection
+                    .delete_many(doc! { "_id": { "$in": stale_ids } }, None)
+                    .await
+                    .context("Could not delete duplicate entries")?;
+                deleted += result.deleted_count;
+            }
+        }
+
+        self.ensure_indexes().await?;
+
+        Ok(deleted)
+    }
+
+    async fn schema_version(&self, collection: &str) -> anyhow::Result<u32> {
+        let meta = self
+            .meta
+            .find_one(doc! { "_id": collection }, None)
+            .await
+            .context("Could not get schema version")?;
+
+        match meta {
+            Some(meta) => Ok(meta.get_i64("schema_version")? as u32),
+            None => Ok(0),
+        }
+    }
+
+    async fn set_schema_version(&self, collection: &str, version: u32) -> anyhow::Result<()> {
+        let options = UpdateOptions::builder().upsert(Some(true)).build();
+
+        self.meta
+            .update_one(
+                doc! { "_id": collection },
+                doc! { "$set": { "schema_version": version as i64 } },
+                options,
+            )
+            .await
+            .context("Could not set schema version")?;
+        Ok(())
+    }
+}
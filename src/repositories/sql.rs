@@ -0,0 +1,987 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use serenity::async_trait;
+use sqlx::{
+    any::{install_default_drivers, AnyPoolOptions, AnyRow},
+    migrate::Migrator,
+    Any, AnyPool, Row, Transaction,
+};
+
+use crate::{
+    repositories::{
+        AuditLogRepository, BackgroundRepository, BlacklistRepository, RequestRepository,
+        SchemaRepository, WebhookDeadLetterRepository,
+    },
+    structs::{
+        AuditAction, AuditEntry, AuditFilter, BackgroundRequest, Blacklist, ChangeOp, Config,
+        DatabaseBackend, DuplicateUids, ImageMetadata, Repositories, RequestStatus, Usrbg,
+        UsrbgChange, WebhookDeadLetter,
+    },
+};
+
+// The two schemas only differ in how generated ids are declared
+static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("migrations/sqlite");
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("migrations/postgres");
+
+/// Connects to `database.url` and brings the schema up to date
+pub async fn connect(config: &Config) -> anyhow::Result<Repositories> {
+    connect_to(&config.database.url, config.database.backend()).await
+}
+
+pub async fn connect_to(url: &str, backend: DatabaseBackend) -> anyhow::Result<Repositories> {
+    install_default_drivers();
+
+    let mut pool_options = AnyPoolOptions::new();
+    // Every connection to an in-memory SQLite database opens a separate, empty database
+    if url.contains(":memory:") {
+        pool_options = pool_options
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+    }
+
+    let pool = pool_options
+        .connect(url)
+        .await
+        .context("Error connecting to database")?;
+
+    let migrator = match backend {
+        DatabaseBackend::Postgres => &POSTGRES_MIGRATOR,
+        _ => &SQLITE_MIGRATOR,
+    };
+    migrator
+        .run(&pool)
+        .await
+        .context("Could not migrate database schema")?;
+
+    Ok(Repositories {
+        backgrounds: Arc::new(SqlBackgrounds { pool: pool.clone() }),
+        blacklist: Arc::new(SqlBlacklist { pool: pool.clone() }),
+        requests: Arc::new(SqlRequests { pool: pool.clone() }),
+        audit_log: Arc::new(SqlAuditLog { pool: pool.clone() }),
+        dead_letters: Arc::new(SqlDeadLetters { pool: pool.clone() }),
+        schema: Arc::new(SqlSchema { pool }),
+    })
+}
+
+fn to_millis(date_time: Option<bson::DateTime>) -> Option<i64> {
+    date_time.map(|date_time| date_time.timestamp_millis())
+}
+
+fn from_millis(millis: Option<i64>) -> Option<bson::DateTime> {
+    millis.map(bson::DateTime::from_millis)
+}
+
+fn image_metadata_from_row(row: &AnyRow) -> anyhow::Result<ImageMetadata> {
+    Ok(ImageMetadata {
+        content_hash: row.try_get("content_hash")?,
+        width: row
+            .try_get::<Option<i64>, _>("width")?
+            .map(|width| width as u32),
+        height: row
+            .try_get::<Option<i64>, _>("height")?
+            .map(|height| height as u32),
+        byte_size: row
+            .try_get::<Option<i64>, _>("byte_size")?
+            .map(|byte_size| byte_size as u64),
+        mime_type: row.try_get("mime_type")?,
+    })
+}
+
+pub struct SqlBackgrounds {
+    pool: AnyPool,
+}
+
+impl SqlBackgrounds {
+    /// Appends a change inside its transaction. Taking the next revision locks the counter
+    /// until the transaction commits, so concurrent changes commit in revision order.
+    async fn record_change(
+        transaction: &mut Transaction<'_, Any>,
+        uid: &str,
+        op: ChangeOp,
+        img: Option<String>,
+    ) -> anyhow::Result<()> {
+        let row =
+            sqlx::query("UPDATE usrbg_revision SET revision = revision + 1 RETURNING revision")
+                .fetch_one(&mut **transaction)
+                .await
+                .context("Could not increment revision")?;
+        let revision: i64 = row.try_get("revision")?;
+
+        sqlx::query(
+            "INSERT INTO usrbg_changes (revision, uid, op, img, created_at)
+            VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(revision)
+        .bind(uid)
+        .bind(op.as_str())
+        .bind(img)
+        .bind(bson::DateTime::now().timestamp_millis())
+        .execute(&mut **transaction)
+        .await
+        .context("Could not insert change")?;
+        Ok(())
+    }
+
+    fn from_row(row: &AnyRow) -> anyhow::Result<Usrbg> {
+        Ok(Usrbg {
+            uid: row.try_get("uid")?,
+            img: row.try_get("img")?,
+            approved_by: row.try_get("approved_by")?,
+            approved_at: from_millis(row.try_get("approved_at")?),
+            source_message_id: row.try_get("source_message_id")?,
+            storage_key: row.try_get("storage_key")?,
+            image: image_metadata_from_row(row)?,
+        })
+    }
+}
+
+#[async_trait]
+impl BackgroundRepository for SqlBackgrounds {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Usrbg>> {
+        let row = sqlx::query("SELECT * FROM usrbg WHERE uid = $1")
+            .bind(uid)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Could not complete usrbg search")?;
+        row.as_ref().map(Self::from_row).transpose()
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Usrbg>> {
+        let rows = sqlx::query("SELECT * FROM usrbg")
+            .fetch_all(&self.pool)
+            .await
+            .context("Could not list usrbg entries")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    async fn upsert(&self, entry: Usrbg) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+        let (uid, img) = (entry.uid.clone(), entry.img.clone());
+
+        sqlx::query(
+            "INSERT INTO usrbg (uid, img, approved_by, approved_at, source_message_id, storage_key,
+                content_hash, width, height, byte_size, mime_type)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (uid) DO UPDATE SET img = excluded.img,
+                approved_by = excluded.approved_by, approved_at = excluded.approved_at,
+                source_message_id = excluded.source_message_id,
+                storage_key = excluded.storage_key, content_hash = excluded.content_hash,
+                width = excluded.width, height = excluded.height,
+                byte_size = excluded.byte_size, mime_type = excluded.mime_type",
+        )
+        .bind(entry.uid)
+        .bind(entry.img)
+        .bind(entry.approved_by)
+        .bind(to_millis(entry.approved_at))
+        .bind(entry.source_message_id)
+        .bind(entry.storage_key)
+        .bind(entry.image.content_hash)
+        .bind(entry.image.width.map(|width| width as i64))
+        .bind(entry.image.height.map(|height| height as i64))
+        .bind(entry.image.byte_size.map(|byte_size| byte_size as i64))
+        .bind(entry.image.mime_type)
+        .execute(&mut *transaction)
+        .await
+        .context("Could not upsert usrbg entry")?;
+
+        Self::record_change(&mut transaction, &uid, ChangeOp::Upsert, Some(img)).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let mut transaction = self.pool.begin().await?;
+
+        let result = sqlx::query("DELETE FROM usrbg WHERE uid = $1")
+            .bind(uid)
+            .execute(&mut *transaction)
+            .await
+            .context("Could not delete usrbg entry")?;
+        let deleted = result.rows_affected() > 0;
+
+        if deleted {
+            Self::record_change(&mut transaction, uid, ChangeOp::Delete, None).await?;
+        }
+        transaction.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn revision(&self) -> anyhow::Result<i64> {
+        let row = sqlx::query("SELECT MAX(revision) AS revision FROM usrbg_changes")
+            .fetch_one(&self.pool)
+            .await
+            .context("Could not get revision")?;
+        Ok(row.try_get::<Option<i64>, _>("revision")?.unwrap_or(0))
+    }
+
+    async fn changes_since(&self, revision: i64, limit: i64) -> anyhow::Result<Vec<UsrbgChange>> {
+        let rows = sqlx::query(
+            "SELECT * FROM usrbg_changes WHERE revision > $1 ORDER BY revision LIMIT $2",
+        )
+        .bind(revision)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .context("Could not list changes")?;
+
+        rows.iter()
+            .map(|row| {
+                let op: String = row.try_get("op")?;
+                Ok(UsrbgChange {
+                    revision: row.try_get("revision")?,
+                    uid: row.try_get("uid")?,
+                    op: match op.as_str() {
+                        "upsert" => ChangeOp::Upsert,
+                        "delete" => ChangeOp::Delete,
+                        _ => bail!("Unknown change op {}", op),
+                    },
+                    img: row.try_get("img")?,
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct SqlBlacklist {
+    pool: AnyPool,
+}
+
+impl SqlBlacklist {
+    fn from_row(row: &AnyRow) -> anyhow::Result<Blacklist> {
+        Ok(Blacklist {
+            uid: row.try_get("uid")?,
+            reason: row.try_get("reason")?,
+            moderator: row.try_get("moderator")?,
+            created_at: from_millis(row.try_get("created_at")?),
+            expires_at: from_millis(row.try_get("expires_at")?),
+        })
+    }
+}
+
+#[async_trait]
+impl BlacklistRepository for SqlBlacklist {
+    async fn get(&self, uid: &str) -> anyhow::Result<Option<Blacklist>> {
+        let row = sqlx::query("SELECT * FROM blacklist WHERE uid = $1")
+            .bind(uid)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Could not complete blacklist search")?;
+        row.as_ref().map(Self::from_row).transpose()
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<Blacklist>> {
+        let rows = sqlx::query("SELECT * FROM blacklist")
+            .fetch_all(&self.pool)
+            .await
+            .context("Could not list blacklist entries")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    async fn upsert(&self, entry: Blacklist) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO blacklist (uid, reason, moderator, created_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (uid) DO UPDATE SET reason = excluded.reason,
+                moderator = excluded.moderator, created_at = excluded.created_at,
+                expires_at = excluded.expires_at",
+        )
+        .bind(entry.uid)
+        .bind(entry.reason)
+        .bind(entry.moderator)
+        .bind(to_millis(entry.created_at))
+        .bind(to_millis(entry.expires_at))
+        .execute(&self.pool)
+        .await
+        .context("Could not upsert blacklist entry")?;
+        Ok(())
+    }
+
+    async fn delete(&self, uid: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM blacklist WHERE uid = $1")
+            .bind(uid)
+            .execute(&self.pool)
+            .await
+            .context("Could not delete blacklist entry")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_expired(&self) -> anyhow::Result<u64> {
+        let result = sqlx::query("DELETE FROM blacklist WHERE expires_at <= $1")
+            .bind(bson::DateTime::now().timestamp_millis())
+            .execute(&self.pool)
+            .await
+            .context("Could not delete expired blacklist entries")?;
+        Ok(result.rows_affected())
+    }
+}
+
+pub struct SqlRequests {
+    pool: AnyPool,
+}
+
+impl SqlRequests {
+    fn from_row(row: &AnyRow) -> anyhow::Result<BackgroundRequest> {
+        Ok(BackgroundRequest {
+            uid: row.try_get("uid")?,
+            request_message_id: row.try_get("request_message_id")?,
+            log_message_id: row.try_get("log_message_id")?,
+            image_url: row.try_get("image_url")?,
+            image: image_metadata_from_row(row)?,
+            status: bson::from_bson(bson::Bson::String(row.try_get("status")?))?,
+            created_at: bson::DateTime::from_millis(row.try_get("created_at")?),
+            decided_at: from_millis(row.try_get("decided_at")?),
+            decided_by: row.try_get("decided_by")?,
+        })
+    }
+}
+
+fn status_to_string(status: RequestStatus) -> anyhow::Result<String> {
+    match bson::to_bson(&status)? {
+        bson::Bson::String(status) => Ok(status),
+        _ => unreachable!(),
+    }
+}
+
+#[async_trait]
+impl RequestRepository for SqlRequests {
+    async fn create(&self, request: BackgroundRequest) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO requests (request_message_id, uid, log_message_id, image_url,
+                content_hash, width, height, byte_size, mime_type, status, created_at,
+                decided_at, decided_by)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+        )
+        .bind(request.request_message_id)
+        .bind(request.uid)
+        .bind(request.log_message_id)
+        .bind(request.image_url)
+        .bind(request.image.content_hash)
+        .bind(request.image.width.map(|width| width as i64))
+        .bind(request.image.height.map(|height| height as i64))
+        .bind(request.image.byte_size.map(|byte_size| byte_size as i64))
+        .bind(request.image.mime_type)
+        .bind(status_to_string(request.status)?)
+        .bind(request.created_at.timestamp_millis())
+        .bind(to_millis(request.decided_at))
+        .bind(request.decided_by)
+        .execute(&self.pool)
+        .await
+        .context("Could not insert request")?;
+        Ok(())
+    }
+
+    async fn get(&self, request_message_id: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        let row = sqlx::query("SELECT * FROM requests WHERE request_message_id = $1")
+            .bind(request_message_id)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Could not find request")?;
+        row.as_ref().map(Self::from_row).transpose()
+    }
+
+    async fn all(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let rows = sqlx::query("SELECT * FROM requests")
+            .fetch_all(&self.pool)
+            .await
+            .context("Could not list requests")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    async fn pending_for_user(&self, uid: &str) -> anyhow::Result<Option<BackgroundRequest>> {
+        let row = sqlx::query("SELECT * FROM requests WHERE uid = $1 AND status = $2")
+            .bind(uid)
+            .bind(status_to_string(RequestStatus::Pending)?)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Could not find pending request")?;
+        row.as_ref().map(Self::from_row).transpose()
+    }
+
+    async fn pending(&self) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let rows = sqlx::query("SELECT * FROM requests WHERE status = $1 ORDER BY created_at")
+            .bind(status_to_string(RequestStatus::Pending)?)
+            .fetch_all(&self.pool)
+            .await
+            .context("Could not get pending requests")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    async fn count_pending(&self) -> anyhow::Result<u64> {
+        let row = sqlx::query("SELECT COUNT(*) AS pending FROM requests WHERE status = $1")
+            .bind(status_to_string(RequestStatus::Pending)?)
+            .fetch_one(&self.pool)
+            .await
+            .context("Could not count pending requests")?;
+        Ok(row.try_get::<i64, _>("pending")? as u64)
+    }
+
+    async fn for_user(
+        &self,
+        uid: &str,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<BackgroundRequest>> {
+        let query = match limit {
+            Some(limit) => sqlx::query(
+                "SELECT * FROM requests WHERE uid = $1 ORDER BY created_at DESC LIMIT $2",
+            )
+            .bind(uid)
+            .bind(limit as i64),
+            None => sqlx::query("SELECT * FROM requests WHERE uid = $1 ORDER BY created_at DESC")
+                .bind(uid),
+        };
+        let rows = query
+            .fetch_all(&self.pool)
+            .await
+            .context("Could not get requests for user")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    async fn resolve(
+        &self,
+        request_message_id: &str,
+        status: RequestStatus,
+        decided_by: Option<String>,
+    ) -> anyhow::Result<Option<BackgroundRequest>> {
+        let result = sqlx::query(
+            "UPDATE requests SET status = $1, decided_at = $2, decided_by = $3
+            WHERE request_message_id = $4 AND status = $5",
+        )
+        .bind(status_to_string(status)?)
+        .bind(bson::DateTime::now().timestamp_millis())
+        .bind(decided_by)
+        .bind(request_message_id)
+        .bind(status_to_string(RequestStatus::Pending)?)
+        .execute(&self.pool)
+        .await
+        .context("Could not update request")?;
+
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.get(request_message_id).await
+    }
+
+    async fn reopen(&self, request_message_id: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE requests SET status = $1, decided_at = NULL, decided_by = NULL
+            WHERE request_message_id = $2",
+        )
+        .bind(status_to_string(RequestStatus::Pending)?)
+        .bind(request_message_id)
+        .execute(&self.pool)
+        .await
+        .context("Could not reopen request")?;
+        Ok(())
+    }
+}
+
+pub struct SqlAuditLog {
+    pool: AnyPool,
+}
+
+impl SqlAuditLog {
+    fn from_row(row: &AnyRow) -> anyhow::Result<AuditEntry> {
+        let action: String = row.try_get("action")?;
+        Ok(AuditEntry {
+            action: AuditAction::ALL
+                .into_iter()
+                .find(|known_action| known_action.as_str() == action)
+                .with_context(|| format!("Unknown audit log action {}", action))?,
+            actor: row.try_get("actor")?,
+            target: row.try_get("target")?,
+            reason: row.try_get("reason")?,
+            before_img: row.try_get("before_img")?,
+            after_img: row.try_get("after_img")?,
+            created_at: bson::DateTime::from_millis(row.try_get("created_at")?),
+        })
+    }
+}
+
+#[async_trait]
+impl AuditLogRepository for SqlAuditLog {
+    async fn append(&self, entry: AuditEntry) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (action, actor, target, reason, before_img, after_img, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(entry.action.as_str())
+        .bind(entry.actor)
+        .bind(entry.target)
+        .bind(entry.reason)
+        .bind(entry.before_img)
+        .bind(entry.after_img)
+        .bind(entry.created_at.timestamp_millis())
+        .execute(&self.pool)
+        .await
+        .context("Could not insert audit log entry")?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        filter: &AuditFilter,
+        skip: u64,
+        limit: Option<u64>,
+    ) -> anyhow::Result<Vec<AuditEntry>> {
+        // Unset filters compare the column to itself so the parameters stay in a fixed order
+        let rows = sqlx::query(
+            "SELECT * FROM audit_log
+            WHERE target = COALESCE($1, target) AND actor = COALESCE($2, actor)
+                AND action = COALESCE($3, action)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $4 OFFSET $5",
+        )
+        .bind(filter.target.clone())
+        .bind(filter.actor.clone())
+        .bind(filter.action.map(|action| action.as_str()))
+        .bind(limit.map_or(i64::MAX, |limit| limit as i64))
+        .bind(skip as i64)
+        .fetch_all(&self.pool)
+        .await
+        .context("Could not search audit log")?;
+        rows.iter().map(Self::from_row).collect()
+    }
+}
+
+pub struct SqlDeadLetters {
+    pool: AnyPool,
+}
+
+#[async_trait]
+impl WebhookDeadLetterRepository for SqlDeadLetters {
+    async fn append(&self, dead_letter: WebhookDeadLetter) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO webhook_dead_letters (url, event, payload, error, attempts, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(dead_letter.url)
+        .bind(dead_letter.event.as_str())
+        .bind(dead_letter.payload)
+        .bind(dead_letter.error)
+        .bind(dead_letter.attempts as i64)
+        .bind(dead_letter.created_at.timestamp_millis())
+        .execute(&self.pool)
+        .await
+        .context("Could not insert webhook dead letter")?;
+        Ok(())
+    }
+}
+
+/// Tables are created by the versioned migrations and keyed by uid, so there are no
+/// indexes to create and duplicates cannot exist
+pub struct SqlSchema {
+    pool: AnyPool,
+}
+
+#[async_trait]
+impl SchemaRepository for SqlSchema {
+    async fn ensure_indexes(&self) -> anyhow::Result<Vec<DuplicateUids>> {
+        Ok(vec![])
+    }
+
+    async fn resolve_duplicates(&self) -> anyhow::Result<u64> {
+        Ok(0)
+    }
+
+    async fn schema_version(&self, collection: &str) -> anyhow::Result<u32> {
+        let row = sqlx::query("SELECT schema_version FROM meta WHERE collection = $1")
+            .bind(collection)
+            .fetch_optional(&self.pool)
+            .await
+            .context("Could not get schema version")?;
+
+        match row {
+            Some(row) => Ok(row.try_get::<i64, _>("schema_version")? as u32),
+            None => Ok(0),
+        }
+    }
+
+    async fn set_schema_version(&self, collection: &str, version: u32) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO meta (collection, schema_version) VALUES ($1, $2)
+            ON CONFLICT (collection) DO UPDATE SET schema_version = excluded.schema_version",
+        )
+        .bind(collection)
+        .bind(version as i64)
+        .execute(&self.pool)
+        .await
+        .context("Could not set schema version")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        migrations::USRBG_SCHEMA,
+        repositories::fixtures::{
+            background, ban, moderator, pending_request, OTHER_UID, REQUEST_MESSAGE_ID, UID,
+        },
+    };
+
+    async fn repositories() -> Repositories {
+        connect_to("sqlite::memory:", DatabaseBackend::Sqlite)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrations_create_an_empty_schema() {
+        let repositories = repositories().await;
+
+        assert!(repositories.backgrounds.all().await.unwrap().is_empty());
+        assert_eq!(repositories.backgrounds.revision().await.unwrap(), 0);
+        assert!(repositories.blacklist.all().await.unwrap().is_empty());
+        assert!(repositories.requests.all().await.unwrap().is_empty());
+        assert!(repositories
+            .schema
+            .ensure_indexes()
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            repositories
+                .schema
+                .schema_version(USRBG_SCHEMA)
+                .await
+                .unwrap(),
+            0
+        );
+        repositories
+            .schema
+            .set_schema_version(USRBG_SCHEMA, 1)
+            .await
+            .unwrap();
+        repositories
+            .schema
+            .set_schema_version(USRBG_SCHEMA, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            repositories
+                .schema
+                .schema_version(USRBG_SCHEMA)
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn backgrounds_round_trip_and_are_replaced() {
+        let repositories = repositories().await;
+
+        let entry = background(UID, "https://example.com/first.png");
+        repositories
+            .backgrounds
+            .upsert(entry.clone())
+            .await
+            .unwrap();
+        let stored = repositories.backgrounds.get(UID).await.unwrap().unwrap();
+        assert_eq!(stored.img, entry.img);
+        assert_eq!(stored.approved_at, entry.approved_at);
+        assert_eq!(stored.image.width, Some(1920));
+        assert_eq!(stored.image.mime_type.as_deref(), Some("image/png"));
+
+        // Fields the new entry leaves unset do not keep their old values
+        let mut replacement = background(UID, "https://example.com/second.gif");
+        replacement.image = ImageMetadata::default();
+        repositories.backgrounds.upsert(replacement).await.unwrap();
+        let stored = repositories.backgrounds.get(UID).await.unwrap().unwrap();
+        assert_eq!(stored.img, "https://example.com/second.gif");
+        assert_eq!(stored.image.width, None);
+        assert_eq!(stored.image.content_hash, None);
+        assert_eq!(repositories.backgrounds.all().await.unwrap().len(), 1);
+
+        assert!(repositories.backgrounds.delete(UID).await.unwrap());
+        assert!(!repositories.backgrounds.delete(UID).await.unwrap());
+        assert!(repositories.backgrounds.get(UID).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn changes_are_logged_in_revision_order() {
+        let repositories = repositories().await;
+
+        repositories
+            .backgrounds
+            .upsert(background(UID, "https://example.com/first.png"))
+            .await
+            .unwrap();
+        repositories
+            .backgrounds
+            .upsert(background(OTHER_UID, "https://example.com/other.png"))
+            .await
+            .unwrap();
+        repositories.backgrounds.delete(UID).await.unwrap();
+        // Deleting nothing is not a change
+        repositories.backgrounds.delete(UID).await.unwrap();
+
+        assert_eq!(repositories.backgrounds.revision().await.unwrap(), 3);
+
+        let changes = repositories.backgrounds.changes_since(0, 10).await.unwrap();
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|change| (change.revision, change.uid.as_str(), change.op))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (1, UID, ChangeOp::Upsert),
+                (2, OTHER_UID, ChangeOp::Upsert),
+                (3, UID, ChangeOp::Delete),
+            ]
+        );
+
+        let changes = repositories.backgrounds.changes_since(1, 1).await.unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].revision, 2);
+        assert_eq!(
+            changes[0].img.as_deref(),
+            Some("https://example.com/other.png")
+        );
+        assert!(repositories
+            .backgrounds
+            .changes_since(3, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrent_changes_get_contiguous_revisions() {
+        let repositories = repositories().await;
+
+        let upserts = (0..20).map(|index| {
+            let backgrounds = repositories.backgrounds.clone();
+            async move {
+                let uid = format!("{}{}", UID, index);
+                backgrounds
+                    .upsert(background(&uid, "https://example.com/image.png"))
+                    .await
+            }
+        });
+        for result in futures_util::future::join_all(upserts).await {
+            result.unwrap();
+        }
+
+        let revisions: Vec<i64> = repositories
+            .backgrounds
+            .changes_since(0, 100)
+            .await
+            .unwrap()
+            .iter()
+            .map(|change| change.revision)
+            .collect();
+        assert_eq!(revisions, (1..=20).collect::<Vec<i64>>());
+        assert_eq!(repositories.backgrounds.revision().await.unwrap(), 20);
+    }
+
+    #[tokio::test]
+    async fn expired_bans_are_deleted() {
+        let repositories = repositories().await;
+        let now = bson::DateTime::now().timestamp_millis();
+
+        repositories
+            .blacklist
+            .upsert(ban(UID, Some(now - 1000)))
+            .await
+            .unwrap();
+        repositories
+            .blacklist
+            .upsert(ban(OTHER_UID, None))
+            .await
+            .unwrap();
+        repositories
+            .blacklist
+            .upsert(ban("80351110224678914", Some(now + 60_000)))
+            .await
+            .unwrap();
+
+        assert_eq!(repositories.blacklist.delete_expired().await.unwrap(), 1);
+        assert!(repositories.blacklist.get(UID).await.unwrap().is_none());
+        assert_eq!(repositories.blacklist.all().await.unwrap().len(), 2);
+
+        // Re-banning replaces the expiry
+        repositories
+            .blacklist
+            .upsert(ban(OTHER_UID, Some(now + 1000)))
+            .await
+            .unwrap();
+        let stored = repositories
+            .blacklist
+            .get(OTHER_UID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stored.expires_at,
+            Some(bson::DateTime::from_millis(now + 1000))
+        );
+
+        assert!(repositories.blacklist.delete(OTHER_UID).await.unwrap());
+        assert!(!repositories.blacklist.delete(OTHER_UID).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn requests_are_resolved_once_and_can_be_reopened() {
+        let repositories = repositories().await;
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID, UID, 1000))
+            .await
+            .unwrap();
+        repositories
+            .requests
+            .create(pending_request(REQUEST_MESSAGE_ID + 2, OTHER_UID, 500))
+            .await
+            .unwrap();
+
+        assert_eq!(repositories.requests.count_pending().await.unwrap(), 2);
+        let pending = repositories.requests.pending().await.unwrap();
+        let pending: Vec<_> = pending.iter().map(|request| request.uid.as_str()).collect();
+        assert_eq!(pending, vec![OTHER_UID, UID]);
+
+        let request_message_id = REQUEST_MESSAGE_ID.to_string();
+        let resolved = repositories
+            .requests
+            .resolve(
+                &request_message_id,
+                RequestStatus::Approved,
+                Some(moderator().to_string()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved.status, RequestStatus::Approved);
+        assert_eq!(resolved.decided_by, Some(moderator().to_string()));
+        assert!(resolved.decided_at.is_some());
+        assert!(repositories
+            .requests
+            .resolve(&request_message_id, RequestStatus::Denied, None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repositories
+            .requests
+            .pending_for_user(UID)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(repositories.requests.count_pending().await.unwrap(), 1);
+
+        repositories
+            .requests
+            .reopen(&request_message_id)
+            .await
+            .unwrap();
+        let reopened = repositories
+            .requests
+            .pending_for_user(UID)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reopened.decided_at, None);
+        assert_eq!(reopened.decided_by, None);
+    }
+
+    #[tokio::test]
+    async fn requests_for_a_user_are_newest_first() {
+        let repositories = repositories().await;
+        for (request_message_id, created_at) in [(1, 1000), (2, 3000), (3, 2000)] {
+            repositories
+                .requests
+                .create(pending_request(request_message_id, UID, created_at))
+                .await
+                .unwrap();
+        }
+
+        let requests = repositories.requests.for_user(UID, Some(2)).await.unwrap();
+        let ids: Vec<_> = requests
+            .iter()
+            .map(|request| request.request_message_id.as_str())
+            .collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert_eq!(
+            repositories
+                .requests
+                .for_user(UID, None)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        assert!(repositories
+            .requests
+            .for_user(OTHER_UID, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn audit_log_is_searched_newest_first() {
+        let repositories = repositories().await;
+        for (index, action) in [AuditAction::Ban, AuditAction::Approve, AuditAction::Ban]
+            .into_iter()
+            .enumerate()
+        {
+            let mut entry = AuditEntry::new(action, OTHER_UID, UID);
+            entry.reason = Some(index.to_string());
+            entry.created_at = bson::DateTime::from_millis(1000 * index as i64);
+            repositories.audit_log.append(entry).await.unwrap();
+        }
+        repositories
+            .audit_log
+            .append(AuditEntry::new(AuditAction::Unban, UID, OTHER_UID))
+            .await
+            .unwrap();
+
+        let filter = AuditFilter {
+            target: Some(UID.to_owned()),
+            ..Default::default()
+        };
+        let entries = repositories
+            .audit_log
+            .search(&filter, 0, None)
+            .await
+            .unwrap();
+        let reasons: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.reason.as_deref().unwrap())
+            .collect();
+        assert_eq!(reasons, vec!["2", "1", "0"]);
+
+        let filter = AuditFilter {
+            action: Some(AuditAction::Ban),
+            ..Default::default()
+        };
+        let entries = repositories
+            .audit_log
+            .search(&filter, 1, Some(1))
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].reason.as_deref(), Some("0"));
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_stored() {
+        let repositories = repositories().await;
+        repositories
+            .dead_letters
+            .append(WebhookDeadLetter {
+                url: "https://example.com/webhook".to_owned(),
+                event: crate::structs::WebhookEvent::UserBanned,
+                payload: "{}".to_owned(),
+                error: "Webhook returned 500".to_owned(),
+                attempts: 6,
+                created_at: bson::DateTime::now(),
+            })
+            .await
+            .unwrap();
+    }
+}
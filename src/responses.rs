@@ -2,16 +2,19 @@ use anyhow::Context as AnyhowContext;
 use serenity::{
     all::{ButtonStyle, Embed, InteractionResponseFlags, MessageFlags, MessageId, UserId},
     builder::{
-        CreateActionRow, CreateButton, CreateEmbed, CreateInteractionResponse,
+        CreateActionRow, CreateAttachment, CreateButton, CreateEmbed, CreateInteractionResponse,
         CreateInteractionResponseFollowup, CreateInteractionResponseMessage, CreateMessage,
-        EditMessage,
+        EditInteractionResponse, EditMessage,
     },
     client::Context,
-    model::{application::ComponentInteraction, channel::Message},
+    model::{
+        application::{CommandInteraction, ComponentInteraction},
+        channel::Message,
+    },
 };
 use url::Url;
 
-use crate::structs::{Config, PendingRequestMidStore, PendingRequestUidStore};
+use crate::structs::Config;
 
 pub async fn edit_request(
     ctx: &Context,
@@ -46,18 +49,12 @@ pub async fn edit_request(
 
     let mut embed_builder = CreateEmbed::new().title(message).fields(fields);
 
-    match thumbnail {
-        Some(thumbnail) => {
-            embed_builder = embed_builder.thumbnail(thumbnail);
-        }
-        None => {}
+    if let Some(thumbnail) = thumbnail {
+        embed_builder = embed_builder.thumbnail(thumbnail);
     }
 
-    match link {
-        Some(link) => {
-            embed_builder = embed_builder.url(link);
-        }
-        None => {}
+    if let Some(link) = link {
+        embed_builder = embed_builder.url(link);
     }
 
     msg.edit(
@@ -106,6 +103,19 @@ pub async fn send_ephemeral_interaction_followup_reply(
     Ok(())
 }
 
+/// Replaces the "thinking" placeholder of a deferred command with the reply
+pub async fn edit_deferred_command_reply(
+    ctx: &Context,
+    command: &CommandInteraction,
+    reply: EditInteractionResponse,
+) -> anyhow::Result<()> {
+    command
+        .edit_response(&ctx.http, reply)
+        .await
+        .context("could not edit command response")?;
+    Ok(())
+}
+
 pub async fn send_command_reply(
     msg: Message,
     ctx: Context,
@@ -117,13 +127,46 @@ pub async fn send_command_reply(
     Ok(())
 }
 
-pub async fn create_request_log_message(ctx: &Context, msg: &Message) -> anyhow::Result<MessageId> {
-    let image_url = &msg
-        .attachments
-        .first()
-        .context("Could not get attachment from message")?
-        .url;
+pub async fn send_command_reply_with_embed(
+    msg: Message,
+    ctx: Context,
+    embed: CreateEmbed,
+) -> anyhow::Result<()> {
+    msg.channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().embed(embed).reference_message(&msg),
+        )
+        .await
+        .context("could not reply to message")?;
+    Ok(())
+}
+
+pub async fn send_command_reply_with_file(
+    msg: Message,
+    ctx: Context,
+    response_text: &str,
+    file: Vec<u8>,
+    filename: &str,
+) -> anyhow::Result<()> {
+    msg.channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new()
+                .content(response_text)
+                .add_file(CreateAttachment::bytes(file, filename))
+                .reference_message(&msg),
+        )
+        .await
+        .context("could not reply to message")?;
+    Ok(())
+}
 
+pub async fn create_request_log_message(
+    ctx: &Context,
+    msg: &Message,
+    image_url: &str,
+) -> anyhow::Result<MessageId> {
     let data = ctx.data.read().await;
     let config = data.get::<Config>().context("Could not get config")?;
 
@@ -158,60 +201,36 @@ pub async fn create_request_log_message(ctx: &Context, msg: &Message) -> anyhow:
     Ok(created_message.id)
 }
 
-pub async fn delete_user_request(ctx: &Context, embed: &Embed) -> anyhow::Result<()> {
-    let embed_link = embed.url.clone().context("could not get embed link")?;
-
-    let mut uid: Option<String> = None;
-
-    for field in &embed.fields {
-        match field.name.as_str() {
-            "UID" => {
-                uid = Some(field.value.clone());
-                break;
-            }
-            _ => {}
-        }
-    }
-
-    let uid: String = uid.context("Could not parse uid from embed")?;
-
-    let mut data = ctx.data.write().await;
-    let pending_request_store = data
-        .get_mut::<PendingRequestUidStore>()
-        .context("Could not get pending request store")?;
-
-    let search_uid = UserId::new(uid.parse()?);
-    pending_request_store.remove(&search_uid);
-
-    let pending_request_mid_store = data
-        .get_mut::<PendingRequestMidStore>()
-        .context("Could not get pending request store")?;
-
-    let embed_link = Url::parse(&embed_link).context("Could not parse embed link")?;
-
-    let segments = embed_link
-        .path_segments()
-        .context("could not get segments from embed link")?;
-    let message_id = segments
-        .into_iter()
-        .last()
-        .context("Could not get message ID from link")?;
-
-    let message_id: u64 = message_id.parse().context("Error parsing message id")?;
-
-    pending_request_mid_store.remove(&MessageId::new(message_id));
-
-    drop(data);
+/// Logs a background set by a moderator without a request
+pub async fn create_background_set_log_message(
+    ctx: &Context,
+    uid: &str,
+    moderator: UserId,
+    image_url: &str,
+) -> anyhow::Result<MessageId> {
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
 
-    let segments = embed_link
-        .path_segments()
-        .context("could not get segments from embed link")?;
-    let message_id = segments
-        .into_iter()
-        .last()
-        .context("Could not get message ID from link")?;
+    let created_message = config
+        .server
+        .log_channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().embed(
+                CreateEmbed::new()
+                    .title("Background Set")
+                    .field("UID", uid, true)
+                    .field("Moderator", format!("<@{}>", moderator), true)
+                    .thumbnail(image_url),
+            ),
+        )
+        .await
+        .context("could not create background log message")?;
+    Ok(created_message.id)
+}
 
-    let message_id: u64 = message_id.parse().context("Error parsing message id")?;
+pub async fn delete_user_request(ctx: &Context, embed: &Embed) -> anyhow::Result<()> {
+    let message_id = get_request_message_id(embed)?;
 
     let data = ctx.data.read().await;
     let config = data.get::<Config>().context("Could not get config")?;
@@ -226,3 +245,20 @@ pub async fn delete_user_request(ctx: &Context, embed: &Embed) -> anyhow::Result
     drop(data);
     Ok(())
 }
+
+/// Gets the id of the original request message from the link on a request log embed
+pub fn get_request_message_id(embed: &Embed) -> anyhow::Result<MessageId> {
+    let embed_link = embed.url.clone().context("could not get embed link")?;
+    let embed_link = Url::parse(&embed_link).context("Could not parse embed link")?;
+
+    let mut segments = embed_link
+        .path_segments()
+        .context("could not get segments from embed link")?;
+    let message_id = segments
+        .next_back()
+        .context("Could not get message ID from link")?;
+
+    let message_id: u64 = message_id.parse().context("Error parsing message id")?;
+
+    Ok(MessageId::new(message_id))
+}
@@ -0,0 +1,65 @@
+use std::{future::Future, time::Duration};
+
+// Longest wait between two attempts, in seconds
+const MAX_BACKOFF_SECS: u64 = 300;
+
+/// Runs `operation` until it succeeds or has been retried `retries` times, waiting twice
+/// as long after every failed attempt. Returns the error of the last attempt.
+pub async fn with_retries<T, F, Fut>(retries: u32, mut operation: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt >= retries => return Err(err),
+            Err(_) => {
+                tokio::time::sleep(backoff(attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.saturating_pow(attempt).min(MAX_BACKOFF_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(0), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(9), Duration::from_secs(MAX_BACKOFF_SECS));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+
+    #[tokio::test]
+    async fn stops_after_the_first_success() {
+        let mut attempts = 0;
+        let result = with_retries(3, || {
+            attempts += 1;
+            async { Ok(()) }
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_error() {
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = with_retries(0, || {
+            attempts += 1;
+            async { anyhow::bail!("unreachable host") }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "unreachable host");
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,12 +1,20 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use anyhow::{bail, Context as AnyhowContext};
 use mime::Mime;
 use reqwest::header::CONTENT_TYPE;
-use s3::{creds::Credentials, Bucket, Region};
-use serenity::all::Context;
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use serenity::all::{Context, MessageId};
+use sha2::{Digest, Sha256};
+use tokio::time::timeout;
 
-use crate::structs::{Config, HttpClient, S3Bucket};
+use crate::{
+    cdn::purge_cdn,
+    metrics::METRICS,
+    structs::{Config, HttpClient, ImageMetadata, S3Bucket, Usrbg},
+};
+
+pub const STAGING_PREFIX: &str = "pending/";
 
 pub async fn connect_bucket(config: &Config) -> Result<S3Bucket, anyhow::Error> {
     let region = Region::Custom {
@@ -36,64 +44,232 @@ pub async fn upload_image_to_s3bucket(
     ctx: &Context,
     image_url: String,
     uid: String,
-) -> Result<String, anyhow::Error> {
+) -> Result<(String, ImageMetadata), anyhow::Error> {
     let data = ctx.data.read().await;
-    let http_client = &data
-        .get::<HttpClient>()
-        .context("Could not get http client")?
-        .client;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let path = object_key(config, &uid);
+    drop(data);
 
-    let response = http_client.get(image_url.clone()).send().await?;
-    let content_type_header = response
-        .headers()
-        .get(CONTENT_TYPE)
-        .context("Could not parse content type of image")?
-        .clone();
-    let parsed_content_type = Mime::from_str(content_type_header.to_str()?)?;
-    let extension = parsed_content_type.subtype().to_string();
+    let (url, metadata) = upload_image(ctx, image_url, &path, false).await?;
+
+    purge_cdn(ctx, vec![url.clone()]).await?;
 
-    let image_bytes = response.bytes().await?;
+    Ok((url, metadata))
+}
+
+/// Copies a request attachment into the bucket so the request no longer depends on the
+/// (expiring) Discord CDN URL. Returns the public URL of the staged copy. Moderators'
+/// requests skip the image type check with `allow_any_type`.
+pub async fn stage_image(
+    ctx: &Context,
+    image_url: String,
+    request_message_id: MessageId,
+    allow_any_type: bool,
+) -> Result<(String, ImageMetadata), anyhow::Error> {
+    upload_image(
+        ctx,
+        image_url,
+        &staged_key(request_message_id),
+        allow_any_type,
+    )
+    .await
+}
 
+/// Copies a staged request image to the user's background with a server-side copy.
+/// Returns the public URL and metadata of the promoted image, or `None` if the request has
+/// no staged copy, e.g. for requests made before staging. The staged copy is kept, so the
+/// request can still be approved if storing the background fails; the caller deletes it.
+pub async fn promote_staged_image(
+    ctx: &Context,
+    request_message_id: MessageId,
+    uid: String,
+) -> Result<Option<(String, ImageMetadata)>, anyhow::Error> {
+    let data = ctx.data.read().await;
     let config = data.get::<Config>().context("Could not get config")?;
     let bucket = &data
         .get::<S3Bucket>()
         .context("Could not get bucket")?
         .bucket;
 
-    if !config.settings.image_types.contains(&extension) {
-        bail!("Invalid content-type")
+    let staged_path = staged_key(request_message_id);
+
+    // The metadata is read from the staged copy itself, so it always describes the image
+    // that is promoted
+    let response = match bucket.get_object(&staged_path).await {
+        Ok(response) => response,
+        Err(S3Error::Http(404, _)) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|content_type| Mime::from_str(content_type).ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let metadata = image_metadata(response.bytes(), &content_type);
+
+    let path = object_key(config, &uid);
+
+    let status_code = bucket
+        .copy_object_internal(staged_path.clone(), path.clone())
+        .await?;
+
+    if status_code != 200 {
+        bail!("Error copying staged image in minio")
+    }
+
+    let url = public_url(config, &path);
+
+    drop(data);
+
+    purge_cdn(ctx, vec![url.clone()]).await?;
+
+    Ok(Some((url, metadata)))
+}
+
+pub async fn delete_staged_image(
+    ctx: &Context,
+    request_message_id: MessageId,
+) -> Result<(), anyhow::Error> {
+    let data = ctx.data.read().await;
+    let bucket = &data
+        .get::<S3Bucket>()
+        .context("Could not get bucket")?
+        .bucket;
+
+    let response = bucket.delete_object(staged_key(request_message_id)).await?;
+
+    if response.status_code() != 204 {
+        bail!("Error deleting staged image from minio")
     }
 
-    let path = format!("{}{}", config.storage.storage_path, uid);
+    Ok(())
+}
+
+async fn upload_image(
+    ctx: &Context,
+    image_url: String,
+    path: &str,
+    allow_any_type: bool,
+) -> Result<(String, ImageMetadata), anyhow::Error> {
+    let (image_bytes, content_type) = download_image(ctx, image_url, allow_any_type).await?;
 
+    let data = ctx.data.read().await;
+    let config = data.get::<Config>().context("Could not get config")?;
+    let bucket = &data
+        .get::<S3Bucket>()
+        .context("Could not get bucket")?
+        .bucket;
+
+    let upload_timer = METRICS.s3_upload_timer();
     let response = bucket
-        .put_object_with_content_type(path.clone(), &image_bytes, &parsed_content_type.to_string())
+        .put_object_with_content_type(path, &image_bytes, content_type.as_ref())
         .await?;
+    upload_timer.observe_duration();
 
     if response.status_code() != 200 {
         bail!("Error uploading image to minio")
     }
 
-    Ok(format!(
-        "{}/{}{}{}",
-        config.storage.url, config.storage.bucket_name, config.storage.storage_path, uid
+    Ok((
+        public_url(config, path),
+        image_metadata(&image_bytes, &content_type),
     ))
 }
 
-pub async fn delete_image_from_s3_bucket(ctx: &Context, uid: String) -> Result<(), anyhow::Error> {
-    let data = ctx.data.read().await;
+pub fn image_metadata(image_bytes: &[u8], content_type: &Mime) -> ImageMetadata {
+    // Dimensions are informational only, an unrecognised format is not an error
+    let size = imagesize::blob_size(image_bytes).ok();
 
+    ImageMetadata {
+        content_hash: Some(hex::encode(Sha256::digest(image_bytes))),
+        width: size.as_ref().map(|size| size.width as u32),
+        height: size.as_ref().map(|size| size.height as u32),
+        byte_size: Some(image_bytes.len() as u64),
+        mime_type: Some(content_type.to_string()),
+    }
+}
+
+/// Downloads an image, streaming the body so that downloads stop as soon as they pass
+/// `settings.max_image_size` or stall for longer than the read timeout. Images that are not
+/// one of `settings.image_types` are rejected unless `allow_any_type` is set.
+pub async fn download_image(
+    ctx: &Context,
+    image_url: String,
+    allow_any_type: bool,
+) -> Result<(Vec<u8>, Mime), anyhow::Error> {
+    let data = ctx.data.read().await;
     let config = data.get::<Config>().context("Could not get config")?;
+    let http_client = data
+        .get::<HttpClient>()
+        .context("Could not get http client")?
+        .client
+        .clone();
+
+    let max_image_size = config.settings.max_image_size;
+    let read_timeout = Duration::from_secs(config.settings.download_read_timeout_secs);
+    let image_types = config.settings.image_types.clone();
+
+    drop(data);
+
+    let mut response = timeout(read_timeout, http_client.get(image_url).send())
+        .await
+        .context("Timed out waiting for the image host to respond")?
+        .context("Could not download image")?
+        .error_for_status()
+        .context("Image host returned an error")?;
+
+    let content_type_header = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .context("Could not parse content type of image")?
+        .clone();
+    let parsed_content_type = Mime::from_str(content_type_header.to_str()?)?;
+    let extension = parsed_content_type.subtype().to_string();
+
+    if !allow_any_type && !image_types.contains(&extension) {
+        bail!("Invalid content-type {}", parsed_content_type)
+    }
+
+    if let Some(content_length) = response.content_length() {
+        if content_length > max_image_size {
+            bail!(
+                "Image is {} bytes, larger than the {} byte limit",
+                content_length,
+                max_image_size
+            );
+        }
+    }
+
+    let mut image_bytes = Vec::new();
+    loop {
+        let chunk = timeout(read_timeout, response.chunk())
+            .await
+            .context("Timed out while downloading image")?
+            .context("Could not download image")?;
+
+        match chunk {
+            Some(chunk) => {
+                if image_bytes.len() as u64 + chunk.len() as u64 > max_image_size {
+                    bail!("Image is larger than the {} byte limit", max_image_size);
+                }
+                image_bytes.extend_from_slice(&chunk);
+            }
+            None => break,
+        }
+    }
+
+    Ok((image_bytes, parsed_content_type))
+}
+
+/// Deletes a background image from the bucket. The CDN is left to the caller to purge.
+pub async fn delete_image_from_s3_bucket(ctx: &Context, key: &str) -> Result<(), anyhow::Error> {
+    let data = ctx.data.read().await;
     let bucket = &data
         .get::<S3Bucket>()
         .context("Could not get bucket")?
         .bucket;
 
-    let path = format!("{}{}", config.storage.storage_path, uid);
-
-    let response = bucket
-        .delete_object(path.clone())
-        .await?;
+    let response = bucket.delete_object(key).await?;
 
     if response.status_code() != 204 {
         bail!("Error deleting image from minio")
@@ -101,3 +277,127 @@ pub async fn delete_image_from_s3_bucket(ctx: &Context, uid: String) -> Result<(
 
     Ok(())
 }
+
+pub fn object_key(config: &Config, uid: &str) -> String {
+    format!("{}{}", config.storage.storage_path, uid)
+}
+
+/// Gets the bucket key of a background, or `None` if its image is hosted elsewhere
+/// (e.g. legacy imgur links).
+pub fn referenced_object_key(config: &Config, entry: &Usrbg) -> Option<String> {
+    // Stored whenever the bot uploaded the image itself
+    if let Some(storage_key) = &entry.storage_key {
+        return Some(storage_key.clone());
+    }
+
+    // Older entries only count when they point at exactly the object they would be stored in
+    let key = object_key(config, &entry.uid);
+    if entry.img == public_url(config, &key) || entry.img == bucket_url(config, &key) {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+pub fn staged_key(request_message_id: MessageId) -> String {
+    format!("{}{}", STAGING_PREFIX, request_message_id)
+}
+
+/// Builds the URL clients should use for an object, keeping the S3 endpoint private
+/// whenever `storage.public_url` is configured.
+pub fn public_url(config: &Config, key: &str) -> String {
+    match &config.storage.public_url {
+        Some(public_url) => public_url.replace("{key}", key.trim_start_matches('/')),
+        None => bucket_url(config, key),
+    }
+}
+
+/// URL of an object on the S3 endpoint itself
+fn bucket_url(config: &Config, key: &str) -> String {
+    format!(
+        "{}/{}/{}",
+        config.storage.url,
+        config.storage.bucket_name,
+        key.trim_start_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repositories::fixtures::background;
+
+    fn config(storage_path: &str, public_url: Option<&str>) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            [bot]
+            application_id = 1
+            discord_token = "token"
+
+            [database]
+            url = "sqlite::memory:"
+
+            [storage]
+            url = "https://s3.example.com"
+            access_key = "access"
+            secret_key = "secret"
+            bucket_name = "usrbg"
+            storage_path = ""
+
+            [server]
+            guild_id = 1
+            request_channel_id = 2
+            log_channel_id = 3
+            command_channel_id = 4
+            auth_role_id = 5
+
+            [settings]
+            image_types = ["png"]
+            "#,
+        )
+        .unwrap();
+        config.storage.storage_path = storage_path.to_owned();
+        config.storage.public_url = public_url.map(|public_url| public_url.to_owned());
+        config
+    }
+
+    #[test]
+    fn only_exact_object_urls_are_referenced() {
+        let config = config("", Some("https://cdn.example.com/{key}?v=1"));
+
+        let referenced = background("123", "https://cdn.example.com/123?v=1");
+        assert_eq!(
+            referenced_object_key(&config, &referenced).as_deref(),
+            Some("123")
+        );
+        let legacy = background("123", "https://s3.example.com/usrbg/123");
+        assert_eq!(
+            referenced_object_key(&config, &legacy).as_deref(),
+            Some("123")
+        );
+
+        // Another user's image that happens to end in this uid
+        let other = background("123", "https://cdn.example.com/9123?v=1");
+        assert_eq!(referenced_object_key(&config, &other), None);
+        let external = background("123", "https://i.imgur.com/123");
+        assert_eq!(referenced_object_key(&config, &external), None);
+    }
+
+    #[test]
+    fn stored_keys_are_used_as_they_are() {
+        let config = config("backgrounds/", None);
+
+        let mut stored = background("123", "https://old-cdn.example.com/backgrounds/123");
+        stored.storage_key = Some("backgrounds/123".to_owned());
+        assert_eq!(
+            referenced_object_key(&config, &stored).as_deref(),
+            Some("backgrounds/123")
+        );
+
+        let legacy = background("123", "https://s3.example.com/usrbg/backgrounds/123");
+        assert_eq!(
+            referenced_object_key(&config, &legacy).as_deref(),
+            Some("backgrounds/123")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use ed25519_dalek::{
+    pkcs8::DecodePrivateKey, Signature, Signer, SigningKey, Verifier, VerifyingKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::structs::Config;
+
+/// Detached signature published next to every dataset file as `<file>.sig`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DatasetSignature {
+    pub algorithm: String,
+    /// Hex encoded signature of the exact bytes of the dataset file
+    pub signature: String,
+    /// Hex encoded public key
+    pub public_key: String,
+    /// Hex encoded sha256 of the public key, which clients should pin
+    pub fingerprint: String,
+}
+
+/// Reads an Ed25519 private key in PKCS#8 PEM format, as generated by
+/// `openssl genpkey -algorithm ed25519`
+pub fn load_signing_key(path: &str) -> anyhow::Result<SigningKey> {
+    let pem =
+        fs::read_to_string(path).with_context(|| format!("Could not read signing key {}", path))?;
+    SigningKey::from_pkcs8_pem(&pem).context("Could not parse signing key")
+}
+
+pub fn fingerprint(public_key: &VerifyingKey) -> String {
+    hex::encode(Sha256::digest(public_key.as_bytes()))
+}
+
+pub fn sign_dataset(signing_key: &SigningKey, contents: &[u8]) -> DatasetSignature {
+    let public_key = signing_key.verifying_key();
+    DatasetSignature {
+        algorithm: "ed25519".to_owned(),
+        signature: hex::encode(signing_key.sign(contents).to_bytes()),
+        public_key: hex::encode(public_key.as_bytes()),
+        fingerprint: fingerprint(&public_key),
+    }
+}
+
+/// Fingerprint of the configured signing key, for verifying without giving one
+pub fn configured_fingerprint(config: &Config) -> anyhow::Result<String> {
+    let key_path = config
+        .publish
+        .as_ref()
+        .and_then(|publish| publish.signing_key.as_deref())
+        .context("No fingerprint given and no signing key configured")?;
+    Ok(fingerprint(&load_signing_key(key_path)?.verifying_key()))
+}
+
+/// Verifies a downloaded dataset file against its signature file. The signing key must
+/// match the given fingerprint.
+pub fn verify_dataset(
+    dataset_path: &str,
+    signature_path: &str,
+    expected_fingerprint: &str,
+) -> anyhow::Result<()> {
+    let contents =
+        fs::read(dataset_path).with_context(|| format!("Could not read {}", dataset_path))?;
+    let signature: DatasetSignature = serde_json::from_slice(
+        &fs::read(signature_path).with_context(|| format!("Could not read {}", signature_path))?,
+    )
+    .context("Could not parse signature file")?;
+
+    if signature.algorithm != "ed25519" {
+        bail!("Unsupported signature algorithm {}", signature.algorithm);
+    }
+
+    let public_key_bytes: [u8; 32] = hex::decode(&signature.public_key)?
+        .try_into()
+        .ok()
+        .context("Public key has the wrong length")?;
+    let public_key = VerifyingKey::from_bytes(&public_key_bytes)?;
+
+    if fingerprint(&public_key) != expected_fingerprint.to_lowercase() {
+        bail!(
+            "Dataset was signed by an unknown key with fingerprint {}",
+            fingerprint(&public_key)
+        );
+    }
+
+    let signature_bytes: [u8; 64] = hex::decode(&signature.signature)?
+        .try_into()
+        .ok()
+        .context("Signature has the wrong length")?;
+    public_key
+        .verify(&contents, &Signature::from_bytes(&signature_bytes))
+        .context("Signature does not match the dataset")?;
+
+    Ok(())
+}
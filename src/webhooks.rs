@@ -0,0 +1,164 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context as AnyhowContext};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Serialize;
+use serenity::client::Context;
+use sha2::Sha256;
+
+use crate::{
+    retry::with_retries,
+    structs::{Config, HttpClient, Repositories, Webhook, WebhookDeadLetter, WebhookEvent},
+};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Details of an event, unset fields do not apply to it
+#[derive(Debug, Default, Serialize)]
+pub struct WebhookEventData {
+    /// Uid of the user the event is about
+    pub uid: String,
+    /// Uid of the moderator or user that caused the event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+impl WebhookEventData {
+    pub fn new(uid: impl ToString) -> WebhookEventData {
+        WebhookEventData {
+            uid: uid.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+    created_at: String,
+    data: &'a WebhookEventData,
+}
+
+/// Delivers an event to every webhook subscribed to it in the background. Deliveries are
+/// retried with exponential backoff and dead-lettered once every attempt has failed.
+pub async fn emit_webhook_event(ctx: &Context, event: WebhookEvent, data: WebhookEventData) {
+    let result = spawn_deliveries(ctx, event, data).await;
+    if result.is_err() {
+        println!("{:?}", result);
+    }
+}
+
+async fn spawn_deliveries(
+    ctx: &Context,
+    event: WebhookEvent,
+    data: WebhookEventData,
+) -> anyhow::Result<()> {
+    let ctx_data = ctx.data.read().await;
+    let webhooks: Vec<Webhook> = ctx_data
+        .get::<Config>()
+        .context("Could not get config")?
+        .webhooks
+        .iter()
+        .filter(|webhook| webhook.events.contains(&event))
+        .cloned()
+        .collect();
+
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let http_client = ctx_data
+        .get::<HttpClient>()
+        .context("Could not get http client")?
+        .client
+        .clone();
+    let repositories = ctx_data
+        .get::<Repositories>()
+        .context("Could not get repositories")?
+        .clone();
+    drop(ctx_data);
+
+    // Serialized once so every webhook and retry receives the exact same body
+    let payload = serde_json::to_string(&WebhookPayload {
+        event: event.as_str(),
+        created_at: bson::DateTime::now().to_rfc3339_string(),
+        data: &data,
+    })?;
+
+    for webhook in webhooks {
+        let http_client = http_client.clone();
+        let repositories = repositories.clone();
+        let payload = payload.clone();
+        tokio::spawn(async move {
+            let result = with_retries(webhook.retries, || {
+                deliver(&http_client, &webhook, event, &payload)
+            })
+            .await
+            .with_context(|| format!("Could not deliver {} to {}", event.as_str(), webhook.url));
+            if let Err(err) = result {
+                println!("{:?}", err);
+                let dead_letter = WebhookDeadLetter {
+                    url: webhook.url,
+                    event,
+                    payload,
+                    error: format!("{:#}", err),
+                    attempts: webhook.retries + 1,
+                    created_at: bson::DateTime::now(),
+                };
+                let result = repositories.dead_letters.append(dead_letter).await;
+                if result.is_err() {
+                    println!("{:?}", result);
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn deliver(
+    http_client: &Client,
+    webhook: &Webhook,
+    event: WebhookEvent,
+    payload: &str,
+) -> anyhow::Result<()> {
+    // Signed together with the body so a captured request cannot be replayed later
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let signature = sign_payload(&webhook.secret, timestamp, payload)?;
+
+    let response = http_client
+        .post(&webhook.url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header("X-Blackcube-Event", event.as_str())
+        .header("X-Blackcube-Timestamp", timestamp.to_string())
+        .header("X-Blackcube-Signature", format!("sha256={}", signature))
+        .body(payload.to_owned())
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        bail!(
+            "Webhook returned {}: {}",
+            response.status(),
+            response.text().await?
+        );
+    }
+    Ok(())
+}
+
+/// Hex encoded HMAC-SHA256 of `<timestamp>.<payload>`
+fn sign_payload(secret: &str, timestamp: u64, payload: &str) -> anyhow::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}